@@ -0,0 +1,206 @@
+//! Fits the prompt of a conversation into the context window of the model by dropping the oldest turns of the conversation.
+
+use crate::{
+    chat::BuildChatPrompt,
+    error::{PromptError, Result},
+};
+use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole, Tool};
+
+/// A prompt built within a token budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FittedPrompt {
+    /// The prompt.
+    pub prompt: String,
+    /// The number of tokens of the prompt, if they have been counted. The tokens are not counted if the prompt is so short that it surely fits.
+    pub tokens: Option<usize>,
+    /// The number of the oldest messages dropped to fit the prompt into the budget.
+    pub dropped_messages: usize,
+}
+
+/// Builds the prompt of the conversation with at most `budget` tokens.
+///
+/// If the prompt of the whole conversation exceeds the budget, the oldest turns are dropped from `messages`, where a turn is a user message with the messages following it until the next user message. The system messages at the beginning and the last turn are always kept, so the prompt still fails to fit if they alone exceed the budget.
+///
+/// # Arguments
+///
+/// * `template` - The prompt template of the model.
+///
+/// * `messages` - The messages of the conversation. The dropped messages are removed.
+///
+/// * `tools` - The tools the model may call.
+///
+/// * `budget` - The maximum number of tokens of the prompt.
+///
+/// * `count_tokens` - Counts the tokens of a prompt with the tokenizer of the model. A prompt which the model rejects for exceeding its context window may be counted as `usize::MAX` tokens.
+pub fn fit_prompt<T, F>(
+    template: &T,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    tools: &[Tool],
+    budget: usize,
+    mut count_tokens: F,
+) -> Result<FittedPrompt>
+where
+    T: BuildChatPrompt + ?Sized,
+    F: FnMut(&str) -> std::result::Result<usize, String>,
+{
+    let prompt = template.build_with_tools(&mut messages.clone(), tools)?;
+
+    // a token has at least one byte besides the BOS token, so a short prompt surely fits without being tokenized
+    if prompt.len() < budget {
+        return Ok(FittedPrompt {
+            prompt,
+            tokens: None,
+            dropped_messages: 0,
+        });
+    }
+
+    let tokens = count_tokens(&prompt).map_err(PromptError::CountTokens)?;
+    if tokens <= budget {
+        return Ok(FittedPrompt {
+            prompt,
+            tokens: Some(tokens),
+            dropped_messages: 0,
+        });
+    }
+
+    // the positions where a turn starts, after the system messages at the beginning
+    let start = messages
+        .iter()
+        .take_while(|message| message.role == ChatCompletionRole::System)
+        .count();
+    let cuts: Vec<usize> = (start + 1..messages.len())
+        .filter(|&i| messages[i].role == ChatCompletionRole::User)
+        .collect();
+
+    // keeps the system messages at the beginning and the messages from the cut
+    let build = |cut: usize| -> Result<String> {
+        let mut kept = messages[..start].to_vec();
+        kept.extend_from_slice(&messages[cut..]);
+        template.build_with_tools(&mut kept, tools)
+    };
+
+    // find the earliest cut which fits, assuming that dropping more turns never makes the prompt longer
+    let mut fitted: Option<(usize, String, usize)> = None;
+    let mut shortest = tokens;
+    let (mut low, mut high) = (0, cuts.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        let prompt = build(cuts[mid])?;
+        let tokens = count_tokens(&prompt).map_err(PromptError::CountTokens)?;
+        if tokens <= budget {
+            fitted = Some((cuts[mid], prompt, tokens));
+            high = mid;
+        } else {
+            shortest = tokens;
+            low = mid + 1;
+        }
+    }
+
+    match fitted {
+        Some((cut, prompt, tokens)) => {
+            messages.drain(start..cut);
+            Ok(FittedPrompt {
+                prompt,
+                tokens: Some(tokens),
+                dropped_messages: cut - start,
+            })
+        }
+        None => Err(PromptError::PromptTooLong {
+            tokens: shortest,
+            budget,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::chatml::ChatMLPrompt;
+
+    fn conversation() -> Vec<ChatCompletionRequestMessage> {
+        [
+            (ChatCompletionRole::System, "You are a helpful assistant."),
+            (ChatCompletionRole::User, "Who is Robert Oppenheimer?"),
+            (ChatCompletionRole::Assistant, "An American physicist."),
+            (ChatCompletionRole::User, "When was he born?"),
+        ]
+        .into_iter()
+        .map(|(role, content)| ChatCompletionRequestMessage::new(role, content))
+        .collect()
+    }
+
+    #[test]
+    fn drop_oldest_turns() {
+        // the number of tokens of a prompt is its length in bytes
+        let full = ChatMLPrompt.build(&mut conversation()).unwrap();
+        let mut shortest = conversation();
+        shortest.drain(1..3);
+        let shortest = ChatMLPrompt.build(&mut shortest).unwrap();
+
+        let mut messages = conversation();
+        let fitted = fit_prompt(&ChatMLPrompt, &mut messages, &[], full.len(), |prompt| {
+            Ok(prompt.len())
+        })
+        .unwrap();
+        assert_eq!(fitted.prompt, full);
+        assert_eq!(fitted.dropped_messages, 0);
+
+        let fitted = fit_prompt(
+            &ChatMLPrompt,
+            &mut messages,
+            &[],
+            full.len() - 1,
+            |prompt| Ok(prompt.len()),
+        )
+        .unwrap();
+        assert_eq!(fitted.prompt, shortest);
+        assert_eq!(fitted.tokens, Some(shortest.len()));
+        assert_eq!(fitted.dropped_messages, 2);
+        assert_eq!(messages[0].role, ChatCompletionRole::System);
+        assert_eq!(messages[1].content_text(), "When was he born?");
+    }
+
+    #[test]
+    fn fail_if_last_turn_does_not_fit() {
+        let mut messages = conversation();
+        match fit_prompt(&ChatMLPrompt, &mut messages, &[], 10, |prompt| {
+            Ok(prompt.len())
+        }) {
+            Err(PromptError::PromptTooLong { tokens, budget }) => {
+                assert!(tokens > 10);
+                assert_eq!(budget, 10);
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn drop_turns_exceeding_context_window() {
+        let mut shortest = conversation();
+        shortest.drain(1..3);
+        let shortest = ChatMLPrompt.build(&mut shortest).unwrap();
+
+        // the model rejects the prompts longer than the budget
+        let budget = shortest.len();
+        let count_tokens = |prompt: &str| match prompt.len() > budget {
+            true => Ok(usize::MAX),
+            false => Ok(prompt.len()),
+        };
+
+        let mut messages = conversation();
+        let fitted = fit_prompt(&ChatMLPrompt, &mut messages, &[], budget, count_tokens).unwrap();
+        assert_eq!(fitted.prompt, shortest);
+        assert_eq!(fitted.dropped_messages, 2);
+
+        let mut messages = conversation();
+        match fit_prompt(&ChatMLPrompt, &mut messages, &[], budget - 1, count_tokens) {
+            Err(PromptError::PromptTooLong {
+                budget: too_short, ..
+            }) => {
+                assert_eq!(too_short, budget - 1)
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+}
@@ -7,7 +7,7 @@ pub struct Baichuan2ChatPrompt;
 impl Baichuan2ChatPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        format!("{content}", content = system_message.content.as_str())
+        format!("{content}", content = system_message.content_text())
     }
 
     /// Create a user prompt from a chat completion request message.
@@ -76,10 +76,17 @@ impl BuildChatPrompt for Baichuan2ChatPrompt {
         let mut prompt = String::new();
         for message in messages {
             if message.role == ChatCompletionRole::User {
-                prompt =
-                    self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                prompt = self.append_user_message(&prompt, &system_prompt, message.content_text());
             } else if message.role == ChatCompletionRole::Assistant {
-                prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                prompt = self.append_assistant_message(&prompt, message.content_text());
+            } else if message.role == ChatCompletionRole::Tool
+                || message.role == ChatCompletionRole::Function
+            {
+                prompt = self.append_user_message(
+                    &prompt,
+                    &system_prompt,
+                    crate::tools::tool_result_content(message),
+                );
             } else {
                 return Err(crate::error::PromptError::UnknownRole(message.role));
             }
@@ -89,4 +96,8 @@ impl BuildChatPrompt for Baichuan2ChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("用户:")]
+    }
 }
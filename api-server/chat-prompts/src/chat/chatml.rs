@@ -1,17 +1,14 @@
 use super::BuildChatPrompt;
-use crate::error::Result;
-use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole};
+use crate::{error::Result, tools};
+use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole, Tool, ToolCallFunction};
 
-/// Generate prompts for the models using ChatML template.
+/// Generate prompts for the models using ChatML template. The tools are described in the format of the Hermes 2 Pro models.
 #[derive(Debug, Default, Clone)]
 pub struct ChatMLPrompt;
 impl ChatMLPrompt {
-    /// Create a system prompt from a chat completion request message.
-    fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        format!(
-            "<|im_start|>system\n{content}<|im_end|>",
-            content = system_message.content.as_str()
-        )
+    /// Create a system prompt from the content of the system message.
+    fn create_system_prompt(&self, content: &str) -> String {
+        format!("<|im_start|>system\n{content}<|im_end|>", content = content)
     }
 
     /// Create a user prompt from a chat completion request message.
@@ -57,20 +54,49 @@ impl ChatMLPrompt {
             assistant_message = content.as_ref().trim(),
         )
     }
+
+    /// create a tool prompt from the result of a tool call.
+    fn append_tool_message(
+        &self,
+        chat_history: impl AsRef<str>,
+        content: impl AsRef<str>,
+    ) -> String {
+        format!(
+            "{chat_history}\n<|im_start|>tool\n<tool_response>\n{tool_message}\n</tool_response><|im_end|>",
+            chat_history = chat_history.as_ref().trim(),
+            tool_message = content.as_ref().trim(),
+        )
+    }
 }
 impl BuildChatPrompt for ChatMLPrompt {
     fn build(&self, messages: &mut Vec<ChatCompletionRequestMessage>) -> Result<String> {
+        self.build_with_tools(messages, &[])
+    }
+
+    fn build_with_tools(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        tools: &[Tool],
+    ) -> Result<String> {
         if messages.is_empty() {
             return Ok(String::new());
         }
 
         // systemp prompt
-        let system_prompt = if messages[0].role == ChatCompletionRole::System {
-            self.create_system_prompt(&messages[0])
+        let mut system_message = if messages[0].role == ChatCompletionRole::System {
+            messages[0].content_text().into_owned()
         } else {
-            String::from("<|im_start|>system\nAnswer as concisely as possible.<|im_end|>")
-            // String::from("<|im_start|>system\nEnter roleplay mode. You are Steve.\n\nSteve is a nasty little man and solves all his problems by punching people in the face.<|im_end|>")
+            String::from("Answer as concisely as possible.")
+            // String::from("Enter roleplay mode. You are Steve.\n\nSteve is a nasty little man and solves all his problems by punching people in the face.")
         };
+        if !tools.is_empty() {
+            system_message = format!(
+                "{system_message}\n\n{tool_prompt}",
+                system_message = system_message.trim(),
+                tool_prompt = tools::hermes_tool_prompt(tools)?
+            );
+        }
+        let system_prompt = self.create_system_prompt(&system_message);
 
         // append user/assistant messages
         if messages.is_empty() {
@@ -83,13 +109,21 @@ impl BuildChatPrompt for ChatMLPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    let content = match &message.tool_calls {
+                        Some(tool_calls) if !tool_calls.is_empty() => format!(
+                            "{content}\n{tool_calls}",
+                            content = message.content_text().trim(),
+                            tool_calls = tools::hermes_tool_calls(tool_calls)
+                        ),
+                        _ => message.content_text().into_owned(),
+                    };
+                    prompt = self.append_assistant_message(&prompt, content);
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_tool_message(&prompt, message.content_text());
                 }
             }
         }
@@ -102,4 +136,12 @@ impl BuildChatPrompt for ChatMLPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("<|im_end|>"), String::from("<|im_start|>")]
+    }
+
+    fn parse_tool_calls(&self, output: &str) -> (String, Vec<ToolCallFunction>) {
+        tools::parse_tool_calls(output)
+    }
 }
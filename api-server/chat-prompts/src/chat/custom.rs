@@ -0,0 +1,194 @@
+use super::BuildChatPrompt;
+use crate::{
+    error::{PromptError, Result},
+    fim::FimFormat,
+};
+use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Generate prompts for the models using a template loaded from a TOML file, so that a new model family can be served without adding a template to this crate.
+///
+/// Each message is wrapped by the prefix and suffix of its role, and the prompt ends with the generation prefix. For example, the ChatML template can be written as:
+///
+/// ```toml
+/// system_prefix = "<|im_start|>system\n"
+/// system_suffix = "<|im_end|>\n"
+/// user_prefix = "<|im_start|>user\n"
+/// user_suffix = "<|im_end|>\n"
+/// assistant_prefix = "<|im_start|>assistant\n"
+/// assistant_suffix = "<|im_end|>\n"
+/// generation_prefix = "<|im_start|>assistant\n"
+/// default_system_message = "Answer as concisely as possible."
+/// stop = ["<|im_end|>"]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CustomPrompt {
+    /// The text at the beginning of the prompt, e.g. `<s>`.
+    pub bos: String,
+    /// The text appended to each assistant message, e.g. `</s>`. It also stops the generation.
+    pub eos: String,
+    pub system_prefix: String,
+    pub system_suffix: String,
+    pub user_prefix: String,
+    pub user_suffix: String,
+    pub assistant_prefix: String,
+    pub assistant_suffix: String,
+    /// The text at the end of the prompt, which the answer of the model follows. Defaults to `assistant_prefix` if not specified.
+    pub generation_prefix: Option<String>,
+    /// The system message used if the messages contain none. No system prompt is created if it is empty.
+    pub default_system_message: String,
+    /// The extra sequences which stop the generation.
+    pub stop: Vec<String>,
+    /// The format of the fill-in-the-middle prompts, if the model supports them.
+    pub fim: Option<Box<FimFormat>>,
+}
+impl CustomPrompt {
+    /// Loads the template from a TOML file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path.as_ref()).map_err(|e| {
+            PromptError::InvalidTemplate(format!(
+                "Fail to read {path}: {msg}",
+                path = path.as_ref().display(),
+                msg = e
+            ))
+        })?;
+        content.parse()
+    }
+
+    /// Wraps the content with the prefix and suffix of the role. The results of tool and function calls are wrapped as user turns.
+    fn wrap(&self, role: ChatCompletionRole, content: &str) -> String {
+        let (prefix, suffix) = match role {
+            ChatCompletionRole::System => (&self.system_prefix, &self.system_suffix),
+            ChatCompletionRole::User | ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                (&self.user_prefix, &self.user_suffix)
+            }
+            ChatCompletionRole::Assistant => (&self.assistant_prefix, &self.assistant_suffix),
+        };
+
+        let mut text = format!("{prefix}{content}{suffix}", content = content.trim());
+        if role == ChatCompletionRole::Assistant {
+            text.push_str(&self.eos);
+        }
+        text
+    }
+}
+impl std::str::FromStr for CustomPrompt {
+    type Err = PromptError;
+
+    fn from_str(content: &str) -> Result<Self> {
+        toml::from_str(content).map_err(|e| PromptError::InvalidTemplate(e.to_string()))
+    }
+}
+impl BuildChatPrompt for CustomPrompt {
+    fn build(&self, messages: &mut Vec<ChatCompletionRequestMessage>) -> Result<String> {
+        if messages.is_empty() {
+            return Err(PromptError::NoMessages);
+        }
+
+        let mut prompt = self.bos.clone();
+
+        // system prompt
+        if messages[0].role != ChatCompletionRole::System && !self.default_system_message.is_empty()
+        {
+            prompt.push_str(&self.wrap(ChatCompletionRole::System, &self.default_system_message));
+        }
+
+        // append system/user/assistant messages, and the results of tool calls
+        for message in messages.iter() {
+            let content = match message.role {
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    crate::tools::tool_result_content(message)
+                }
+                _ => message.content_text().into_owned(),
+            };
+            prompt.push_str(&self.wrap(message.role, &content));
+        }
+
+        prompt.push_str(
+            self.generation_prefix
+                .as_deref()
+                .unwrap_or(&self.assistant_prefix),
+        );
+
+        Ok(prompt)
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        let mut stops = self.stop.clone();
+        if !self.eos.is_empty() && !stops.contains(&self.eos) {
+            stops.push(self.eos.clone());
+        }
+        stops
+    }
+
+    fn fim_format(&self) -> Option<FimFormat> {
+        self.fim.as_deref().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = r#"
+system_prefix = "<|im_start|>system\n"
+system_suffix = "<|im_end|>\n"
+user_prefix = "<|im_start|>user\n"
+user_suffix = "<|im_end|>\n"
+assistant_prefix = "<|im_start|>assistant\n"
+assistant_suffix = "<|im_end|>\n"
+default_system_message = "Answer as concisely as possible."
+stop = ["<|im_end|>"]
+"#;
+
+    #[test]
+    fn render_chatml() {
+        let template: CustomPrompt = CHATML.parse().unwrap();
+        let mut messages = vec![
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::User,
+                "Who is Robert Oppenheimer?",
+            ),
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::Assistant,
+                " An American physicist. ",
+            ),
+            ChatCompletionRequestMessage::new(ChatCompletionRole::User, "When was he born?"),
+        ];
+
+        // the default system message is used, and the generation prefix defaults to the assistant prefix
+        assert_eq!(
+            template.build(&mut messages).unwrap(),
+            "<|im_start|>system\nAnswer as concisely as possible.<|im_end|>\n<|im_start|>user\nWho is Robert Oppenheimer?<|im_end|>\n<|im_start|>assistant\nAn American physicist.<|im_end|>\n<|im_start|>user\nWhen was he born?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(template.stop_sequences(), vec!["<|im_end|>"]);
+    }
+
+    #[test]
+    fn render_bos_and_eos() {
+        let template: CustomPrompt = "bos = \"<s>\"\neos = \"</s>\"\nuser_prefix = \"[INST] \"\nuser_suffix = \" [/INST]\"\nassistant_prefix = \" \"\ngeneration_prefix = \"\"\n"
+            .parse()
+            .unwrap();
+        let mut messages = vec![
+            ChatCompletionRequestMessage::new(ChatCompletionRole::User, "Hi"),
+            ChatCompletionRequestMessage::new(ChatCompletionRole::Assistant, "Hello"),
+            ChatCompletionRequestMessage::new(ChatCompletionRole::User, "Bye"),
+        ];
+
+        assert_eq!(
+            template.build(&mut messages).unwrap(),
+            "<s>[INST] Hi [/INST] Hello</s>[INST] Bye [/INST]"
+        );
+        assert_eq!(template.stop_sequences(), vec!["</s>"]);
+    }
+
+    #[test]
+    fn reject_invalid_toml() {
+        assert!(matches!(
+            "stop = \"<|im_end|>\"".parse::<CustomPrompt>(),
+            Err(PromptError::InvalidTemplate(_))
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 use super::BuildChatPrompt;
-use crate::error::Result;
+use crate::{error::Result, fim::FimFormat};
 use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole};
 
 /// Generate prompts for the `DeepSeek-LLM-Chat` model.
@@ -32,7 +32,7 @@ impl DeepseekChatPrompt {
         content: impl AsRef<str>,
     ) -> String {
         format!(
-            "{chat_history}\n\nAssistant: {assistant_message}<|end_of_sentence|>",
+            "{chat_history}\n\nAssistant: {assistant_message}<｜end▁of▁sentence｜>",
             chat_history = chat_history.as_ref().trim(),
             assistant_message = content.as_ref().trim(),
         )
@@ -50,13 +50,14 @@ impl BuildChatPrompt for DeepseekChatPrompt {
             match message.role {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
-                    prompt = self.append_user_message(&prompt, message.content.as_str());
+                    prompt = self.append_user_message(&prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self
+                        .append_user_message(&prompt, crate::tools::tool_result_content(message));
                 }
             }
         }
@@ -65,6 +66,10 @@ impl BuildChatPrompt for DeepseekChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("<｜end▁of▁sentence｜>")]
+    }
 }
 
 /// Generate prompts for the `DeepSeek-Coder` model.
@@ -73,7 +78,7 @@ pub struct DeepseekCoderPrompt;
 impl DeepseekCoderPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("You are an AI programming assistant, utilizing the DeepSeek Coder model, developed by DeepSeek Company, and you only answer questions related to computer science. For politically sensitive questions, security and privacy issues, and other non-computer science questions, you will refuse to answer."),
             false => format!(
@@ -140,13 +145,17 @@ impl BuildChatPrompt for DeepseekCoderPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -155,4 +164,12 @@ impl BuildChatPrompt for DeepseekCoderPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("<|EOT|>"), String::from("### Instruction:")]
+    }
+
+    fn fim_format(&self) -> Option<FimFormat> {
+        Some(FimFormat::deepseek_coder())
+    }
 }
@@ -8,7 +8,7 @@ pub struct NeuralChatPrompt;
 impl NeuralChatPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("### System:\nYou are a chatbot developed by Intel. Please answer all questions to the best of your ability."),
             false => format!("### System:\n{content}"),
@@ -73,13 +73,17 @@ impl BuildChatPrompt for NeuralChatPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -88,4 +92,8 @@ impl BuildChatPrompt for NeuralChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("### User:")]
+    }
 }
@@ -0,0 +1,243 @@
+use super::BuildChatPrompt;
+use crate::{
+    error::{PromptError, Result},
+    gguf::GgufMetadata,
+    tools,
+};
+use endpoints::chat::{
+    ChatCompletionRequestMessage, ChatCompletionRole, Tool, ToolCall, ToolCallFunction,
+};
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Serialize;
+use std::{borrow::Cow, path::Path};
+
+/// Generate prompts for the models using a Hugging Face chat template, which is a Jinja template rendered with `messages`, `tools`, `add_generation_prompt`, `bos_token` and `eos_token`. The template is usually shipped in the model file as `tokenizer.chat_template`.
+#[derive(Debug, Clone)]
+pub struct JinjaPrompt {
+    /// The environment holding the compiled chat template, which is parsed once when the prompt template is created.
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+impl JinjaPrompt {
+    /// The name of the chat template in the environment.
+    const TEMPLATE_NAME: &'static str = "chat_template";
+
+    /// Creates a prompt template from the source of a Jinja chat template.
+    pub fn new(
+        template: impl Into<String>,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Result<Self> {
+        let mut env = create_environment();
+        env.add_template_owned(Self::TEMPLATE_NAME, template.into())
+            .map_err(|e| PromptError::InvalidTemplate(e.to_string()))?;
+
+        Ok(Self {
+            env,
+            bos_token: bos_token.into(),
+            eos_token: eos_token.into(),
+        })
+    }
+
+    /// Loads the chat template from the given template file, or from the metadata of the given GGUF model file if no template file is given. The BOS and EOS tokens are read from the model file if it is given.
+    pub fn load(
+        template_file: Option<impl AsRef<Path>>,
+        model_file: Option<impl AsRef<Path>>,
+    ) -> Result<Self> {
+        let metadata = match model_file {
+            Some(model_file) => Some(GgufMetadata::from_file(model_file)?),
+            None => None,
+        };
+
+        let template = match (template_file, &metadata) {
+            (Some(template_file), _) => {
+                std::fs::read_to_string(template_file.as_ref()).map_err(|e| {
+                    PromptError::InvalidTemplate(format!(
+                        "Fail to read {path}: {msg}",
+                        path = template_file.as_ref().display(),
+                        msg = e
+                    ))
+                })?
+            }
+            (None, Some(metadata)) => match metadata.chat_template() {
+                Some(template) => template.to_owned(),
+                None => {
+                    return Err(PromptError::InvalidTemplate(String::from(
+                        "The model file has no chat template",
+                    )))
+                }
+            },
+            (None, None) => {
+                return Err(PromptError::InvalidTemplate(String::from(
+                    "Either a chat template file or a model file is required",
+                )))
+            }
+        };
+
+        let (bos_token, eos_token) = match &metadata {
+            Some(metadata) => (
+                metadata.bos_token().unwrap_or_default(),
+                metadata.eos_token().unwrap_or_default(),
+            ),
+            None => ("", ""),
+        };
+
+        Self::new(template, bos_token, eos_token)
+    }
+}
+impl BuildChatPrompt for JinjaPrompt {
+    fn build(&self, messages: &mut Vec<ChatCompletionRequestMessage>) -> Result<String> {
+        self.build_with_tools(messages, &[])
+    }
+
+    /// Passes the tools to the template, which decides how to describe them. The templates without tools ignore them.
+    fn build_with_tools(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        tools: &[Tool],
+    ) -> Result<String> {
+        if messages.is_empty() {
+            return Err(PromptError::NoMessages);
+        }
+
+        let template = self
+            .env
+            .get_template(Self::TEMPLATE_NAME)
+            .map_err(|e| PromptError::InvalidTemplate(e.to_string()))?;
+        let messages: Vec<TemplateMessage> = messages.iter().map(TemplateMessage::from).collect();
+        template
+            .render(context! {
+                messages => messages,
+                // the templates check whether tools are given with `{% if tools %}`
+                tools => (!tools.is_empty()).then_some(tools),
+                add_generation_prompt => true,
+                bos_token => &self.bos_token,
+                eos_token => &self.eos_token,
+            })
+            .map_err(|e| PromptError::InvalidTemplate(e.to_string()))
+    }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        match self.eos_token.is_empty() {
+            true => vec![],
+            false => vec![self.eos_token.clone()],
+        }
+    }
+
+    fn parse_tool_calls(&self, output: &str) -> (String, Vec<ToolCallFunction>) {
+        tools::parse_tool_calls(output)
+    }
+}
+
+/// A message as expected by the chat templates, whose contents are always a string.
+#[derive(Serialize)]
+struct TemplateMessage<'a> {
+    role: ChatCompletionRole,
+    content: Cow<'a, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<&'a [ToolCall]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+impl<'a> From<&'a ChatCompletionRequestMessage> for TemplateMessage<'a> {
+    fn from(message: &'a ChatCompletionRequestMessage) -> Self {
+        Self {
+            role: message.role,
+            content: message.content_text(),
+            name: message.name.as_deref(),
+            tool_calls: message.tool_calls.as_deref(),
+            tool_call_id: message.tool_call_id.as_deref(),
+        }
+    }
+}
+
+/// Creates the environment for rendering the chat templates, which supports the Python string methods and the `raise_exception` function used by the Hugging Face chat templates.
+fn create_environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function(
+        "raise_exception",
+        |msg: String| -> std::result::Result<String, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, msg))
+        },
+    );
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use endpoints::chat::ChatCompletionRole;
+
+    #[test]
+    fn render_chatml_template() {
+        // the `chat_template` of the ChatML models on Hugging Face, e.g. `teknium/OpenHermes-2.5-Mistral-7B`
+        let template = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+        let prompt = JinjaPrompt::new(template, "<s>", "<|im_end|>").unwrap();
+        let mut messages = vec![
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::System,
+                "You are a helpful assistant.",
+            ),
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::User,
+                "Who is Robert Oppenheimer?",
+            ),
+        ];
+
+        assert_eq!(
+            prompt.build(&mut messages).unwrap(),
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n<|im_start|>user\nWho is Robert Oppenheimer?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(prompt.stop_sequences(), vec!["<|im_end|>"]);
+    }
+
+    #[test]
+    fn render_llama_2_template() {
+        // the `chat_template` of `meta-llama/Llama-2-7b-chat-hf`
+        let template = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+        let prompt = JinjaPrompt::new(template, "<s>", "</s>").unwrap();
+        let mut messages = vec![
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::System,
+                "You are a helpful assistant.",
+            ),
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::User,
+                "Who is Robert Oppenheimer?",
+            ),
+            ChatCompletionRequestMessage::new(
+                ChatCompletionRole::Assistant,
+                "He was an American theoretical physicist.",
+            ),
+            ChatCompletionRequestMessage::new(ChatCompletionRole::User, "When was he born?"),
+        ];
+
+        assert_eq!(
+            prompt.build(&mut messages).unwrap(),
+            "<s>[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\nWho is Robert Oppenheimer? [/INST] He was an American theoretical physicist. </s><s>[INST] When was he born? [/INST]"
+        );
+
+        // the template raises an exception unless the roles alternate
+        messages.pop();
+        messages.push(ChatCompletionRequestMessage::new(
+            ChatCompletionRole::Assistant,
+            "In 1904.",
+        ));
+        assert!(matches!(
+            prompt.build(&mut messages),
+            Err(PromptError::InvalidTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_template() {
+        assert!(matches!(
+            JinjaPrompt::new("{% for message in messages %}", "", ""),
+            Err(PromptError::InvalidTemplate(_))
+        ));
+    }
+}
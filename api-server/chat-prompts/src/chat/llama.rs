@@ -1,5 +1,5 @@
 use super::BuildChatPrompt;
-use crate::error::Result;
+use crate::{error::Result, fim::FimFormat};
 use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole};
 
 /// Generate prompts for the `Llama-2-chat` model.
@@ -8,7 +8,7 @@ pub struct Llama2ChatPrompt;
 impl Llama2ChatPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("<<SYS>>\nYou are a helpful, respectful and honest assistant. Always answer as short as possible, while being safe. <</SYS>>"),
             false =>format!(
@@ -85,13 +85,17 @@ impl BuildChatPrompt for Llama2ChatPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -110,7 +114,7 @@ pub struct CodeLlamaInstructPrompt;
 impl CodeLlamaInstructPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("<<SYS>>\nYou are a helpful, respectful and honest assistant. Always answer as short as possible, while being safe. <</SYS>>"),
             false => format!(
@@ -187,13 +191,17 @@ impl BuildChatPrompt for CodeLlamaInstructPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -204,4 +212,8 @@ impl BuildChatPrompt for CodeLlamaInstructPrompt {
 
         Ok(prompt)
     }
+
+    fn fim_format(&self) -> Option<FimFormat> {
+        Some(FimFormat::code_llama())
+    }
 }
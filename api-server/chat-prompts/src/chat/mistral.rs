@@ -49,13 +49,14 @@ impl BuildChatPrompt for MistralInstructPrompt {
             match message.role {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
-                    prompt = self.append_user_message(&prompt, message.content.as_str());
+                    prompt = self.append_user_message(&prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self
+                        .append_user_message(&prompt, crate::tools::tool_result_content(message));
                 }
             }
         }
@@ -112,13 +113,14 @@ impl BuildChatPrompt for MistralLitePrompt {
             match message.role {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
-                    prompt = self.append_user_message(&prompt, message.content.as_str());
+                    prompt = self.append_user_message(&prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self
+                        .append_user_message(&prompt, crate::tools::tool_result_content(message));
                 }
             }
         }
@@ -127,4 +129,8 @@ impl BuildChatPrompt for MistralLitePrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("</s>")]
+    }
 }
@@ -1,8 +1,10 @@
 pub mod baichuan;
 pub mod belle;
 pub mod chatml;
+pub mod custom;
 pub mod deepseek;
 pub mod intel;
+pub mod jinja;
 pub mod llama;
 pub mod mistral;
 pub mod openchat;
@@ -10,13 +12,19 @@ pub mod vicuna;
 pub mod wizard;
 pub mod zephyr;
 
-use crate::error::Result;
+use crate::{
+    error::{PromptError, Result},
+    fim::FimFormat,
+    PromptTemplateType,
+};
 use baichuan::*;
 use belle::*;
 use chatml::*;
+use custom::*;
 use deepseek::*;
-use endpoints::chat::ChatCompletionRequestMessage;
+use endpoints::chat::{ChatCompletionRequestMessage, Tool, ToolCallFunction};
 use intel::*;
+use jinja::*;
 use llama::*;
 use mistral::*;
 use openchat::*;
@@ -27,9 +35,49 @@ use zephyr::*;
 #[enum_dispatch::enum_dispatch]
 pub trait BuildChatPrompt: Send {
     fn build(&self, messages: &mut Vec<ChatCompletionRequestMessage>) -> Result<String>;
+
+    /// The markers which end the answer of the model in the prompt format. The generation should stop at any of them.
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Cleans up the answer generated by the model. By default, the answer is cut at the first stop sequence, and the surrounding whitespaces are removed.
+    fn post_process(&self, output: &str) -> String {
+        let end = self
+            .stop_sequences()
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| output.find(stop.as_str()))
+            .min()
+            .unwrap_or(output.len());
+        output[..end].trim().to_owned()
+    }
+
+    /// Builds the prompt with the definitions of the tools the model may call. By default, the template has no format for tools, so the prompt can only be built without tools.
+    fn build_with_tools(
+        &self,
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        tools: &[Tool],
+    ) -> Result<String> {
+        match tools.is_empty() {
+            true => self.build(messages),
+            false => Err(PromptError::UnsupportedTools),
+        }
+    }
+
+    /// Extracts the tool calls from the post-processed answer of the model, and returns the text left with the tool calls. By default, the answer has no tool calls.
+    fn parse_tool_calls(&self, output: &str) -> (String, Vec<ToolCallFunction>) {
+        (output.to_owned(), vec![])
+    }
+
+    /// The format of the fill-in-the-middle prompts of the model. By default, the model is not trained to fill in the middle.
+    fn fim_format(&self) -> Option<FimFormat> {
+        None
+    }
 }
 
 #[enum_dispatch::enum_dispatch(BuildChatPrompt)]
+#[derive(Debug, Clone)]
 pub enum ChatPrompt {
     Llama2ChatPrompt,
     MistralInstructPrompt,
@@ -48,4 +96,66 @@ pub enum ChatPrompt {
     NeuralChatPrompt,
     DeepseekChatPrompt,
     DeepseekCoderPrompt,
+    /// User-defined template loaded from a file
+    CustomPrompt,
+    /// Hugging Face chat template in Jinja
+    JinjaPrompt,
+}
+impl TryFrom<PromptTemplateType> for ChatPrompt {
+    type Error = PromptError;
+
+    /// Creates the built-in template of the given type. Fails for [`PromptTemplateType::Custom`] and [`PromptTemplateType::Jinja`], which must be loaded with [`CustomPrompt::from_file`] and [`JinjaPrompt::load`] respectively.
+    fn try_from(template_ty: PromptTemplateType) -> Result<Self> {
+        match template_ty {
+            PromptTemplateType::Llama2Chat => {
+                Ok(ChatPrompt::Llama2ChatPrompt(Llama2ChatPrompt::default()))
+            }
+            PromptTemplateType::MistralInstruct => Ok(ChatPrompt::MistralInstructPrompt(
+                MistralInstructPrompt::default(),
+            )),
+            PromptTemplateType::MistralLite => {
+                Ok(ChatPrompt::MistralLitePrompt(MistralLitePrompt::default()))
+            }
+            PromptTemplateType::OpenChat => {
+                Ok(ChatPrompt::OpenChatPrompt(OpenChatPrompt::default()))
+            }
+            PromptTemplateType::CodeLlama => Ok(ChatPrompt::CodeLlamaInstructPrompt(
+                CodeLlamaInstructPrompt::default(),
+            )),
+            PromptTemplateType::BelleLlama2Chat => Ok(ChatPrompt::BelleLlama2ChatPrompt(
+                BelleLlama2ChatPrompt::default(),
+            )),
+            PromptTemplateType::VicunaChat => {
+                Ok(ChatPrompt::VicunaChatPrompt(VicunaChatPrompt::default()))
+            }
+            PromptTemplateType::Vicuna11Chat => {
+                Ok(ChatPrompt::Vicuna11ChatPrompt(Vicuna11ChatPrompt::default()))
+            }
+            PromptTemplateType::ChatML => Ok(ChatPrompt::ChatMLPrompt(ChatMLPrompt::default())),
+            PromptTemplateType::Baichuan2 => Ok(ChatPrompt::Baichuan2ChatPrompt(
+                Baichuan2ChatPrompt::default(),
+            )),
+            PromptTemplateType::WizardCoder => {
+                Ok(ChatPrompt::WizardCoderPrompt(WizardCoderPrompt::default()))
+            }
+            PromptTemplateType::Zephyr => {
+                Ok(ChatPrompt::ZephyrChatPrompt(ZephyrChatPrompt::default()))
+            }
+            PromptTemplateType::IntelNeural => {
+                Ok(ChatPrompt::NeuralChatPrompt(NeuralChatPrompt::default()))
+            }
+            PromptTemplateType::DeepseekChat => {
+                Ok(ChatPrompt::DeepseekChatPrompt(DeepseekChatPrompt::default()))
+            }
+            PromptTemplateType::DeepseekCoder => Ok(ChatPrompt::DeepseekCoderPrompt(
+                DeepseekCoderPrompt::default(),
+            )),
+            PromptTemplateType::Custom => Err(PromptError::InvalidTemplate(String::from(
+                "The custom template must be loaded from a template file",
+            ))),
+            PromptTemplateType::Jinja => Err(PromptError::InvalidTemplate(String::from(
+                "The Jinja chat template must be loaded from a template file or a model file",
+            ))),
+        }
+    }
 }
@@ -50,13 +50,14 @@ impl BuildChatPrompt for OpenChatPrompt {
             match message.role {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
-                    prompt = self.append_user_message(&prompt, message.content.as_str());
+                    prompt = self.append_user_message(&prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self
+                        .append_user_message(&prompt, crate::tools::tool_result_content(message));
                 }
             }
         }
@@ -65,4 +66,8 @@ impl BuildChatPrompt for OpenChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("<|end_of_turn|>")]
+    }
 }
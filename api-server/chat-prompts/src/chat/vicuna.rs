@@ -8,7 +8,7 @@ pub struct VicunaChatPrompt;
 impl VicunaChatPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("A chat between a curious user and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the user's questions."),
             false => format!("{content}"),
@@ -73,13 +73,17 @@ impl BuildChatPrompt for VicunaChatPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -88,6 +92,10 @@ impl BuildChatPrompt for VicunaChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("USER:")]
+    }
 }
 
 /// Vicuna-1.1 Prompt Template
@@ -139,13 +147,14 @@ impl BuildChatPrompt for Vicuna11ChatPrompt {
             match message.role {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
-                    prompt = self.append_user_message(&prompt, message.content.as_str());
+                    prompt = self.append_user_message(&prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self
+                        .append_user_message(&prompt, crate::tools::tool_result_content(message));
                 }
             }
         }
@@ -154,4 +163,8 @@ impl BuildChatPrompt for Vicuna11ChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("USER:")]
+    }
 }
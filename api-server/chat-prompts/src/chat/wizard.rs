@@ -1,5 +1,5 @@
 use super::BuildChatPrompt;
-use crate::error::Result;
+use crate::{error::Result, fim::FimFormat};
 use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionRole};
 
 /// Generate prompts for the `wizard-vicuna` model.
@@ -8,7 +8,7 @@ pub struct WizardCoderPrompt;
 impl WizardCoderPrompt {
     /// Create a system prompt from a chat completion request message.
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
-        let content = system_message.content.as_str();
+        let content = system_message.content_text();
         match content.is_empty() {
             true => String::from("Below is an instruction that describes a task. Write a response that appropriately completes the request."),
             false => format!("{content}"),
@@ -47,13 +47,26 @@ impl BuildChatPrompt for WizardCoderPrompt {
         }
 
         let message = messages.last().unwrap();
-        if message.role != ChatCompletionRole::User {
-            return Err(crate::error::PromptError::NoMessages);
-        }
-        let mut prompt = self.append_user_message(&system_prompt, message.content.as_str());
+        let mut prompt = match message.role {
+            ChatCompletionRole::User => {
+                self.append_user_message(&system_prompt, message.content_text())
+            }
+            ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                self.append_user_message(&system_prompt, crate::tools::tool_result_content(message))
+            }
+            _ => return Err(crate::error::PromptError::NoMessages),
+        };
 
         prompt.push_str("\n\n### Response:");
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("### Instruction:")]
+    }
+
+    fn fim_format(&self) -> Option<FimFormat> {
+        Some(FimFormat::starcoder())
+    }
 }
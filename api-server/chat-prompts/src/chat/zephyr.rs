@@ -10,7 +10,7 @@ impl ZephyrChatPrompt {
     fn create_system_prompt(&self, system_message: &ChatCompletionRequestMessage) -> String {
         format!(
             "<|system|>\n{content}</s>",
-            content = system_message.content.as_str()
+            content = system_message.content_text()
         )
     }
 
@@ -72,13 +72,17 @@ impl BuildChatPrompt for ZephyrChatPrompt {
                 ChatCompletionRole::System => continue,
                 ChatCompletionRole::User => {
                     prompt =
-                        self.append_user_message(&prompt, &system_prompt, message.content.as_str());
+                        self.append_user_message(&prompt, &system_prompt, message.content_text());
                 }
                 ChatCompletionRole::Assistant => {
-                    prompt = self.append_assistant_message(&prompt, message.content.as_str());
+                    prompt = self.append_assistant_message(&prompt, message.content_text());
                 }
-                _ => {
-                    return Err(crate::error::PromptError::UnknownRole(message.role));
+                ChatCompletionRole::Tool | ChatCompletionRole::Function => {
+                    prompt = self.append_user_message(
+                        &prompt,
+                        &system_prompt,
+                        crate::tools::tool_result_content(message),
+                    );
                 }
             }
         }
@@ -87,4 +91,8 @@ impl BuildChatPrompt for ZephyrChatPrompt {
 
         Ok(prompt)
     }
+
+    fn stop_sequences(&self) -> Vec<String> {
+        vec![String::from("</s>")]
+    }
 }
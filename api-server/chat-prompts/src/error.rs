@@ -11,4 +11,17 @@ pub enum PromptError {
     UnknownRole(ChatCompletionRole),
     #[error("Unknown prompt template type: {0}")]
     UnknownPromptTemplateType(String),
+    #[error("Invalid prompt template: {0}")]
+    InvalidTemplate(String),
+    #[error("Invalid model file: {0}")]
+    InvalidModelFile(String),
+    #[error("The prompt template does not support tools.")]
+    UnsupportedTools,
+    #[error("Invalid tool definition: {0}")]
+    InvalidTool(String),
+    /// `tokens` is the number of tokens of the shortest prompt, which is `usize::MAX` if the prompt exceeds the context window of the model.
+    #[error("The prompt exceeds the budget of {budget} tokens even if only the system messages and the last turn are kept.")]
+    PromptTooLong { tokens: usize, budget: usize },
+    #[error("Fail to count the tokens of the prompt: {0}")]
+    CountTokens(String),
 }
@@ -0,0 +1,117 @@
+//! Builds fill-in-the-middle (FIM) prompts, which ask a code model to generate the code between a prefix and a suffix.
+
+use serde::{Deserialize, Serialize};
+
+/// The sentinel tokens of the fill-in-the-middle prompts of a model.
+///
+/// The prompt is built in the prefix-suffix-middle order, i.e. `{prefix}{code before}{suffix}{code after}{middle}`, and the model generates the code in the middle. For example, the format of the StarCoder models can be written in the TOML file of a custom template as:
+///
+/// ```toml
+/// [fim]
+/// prefix = "<fim_prefix>"
+/// suffix = "<fim_suffix>"
+/// middle = "<fim_middle>"
+/// stop = ["<|endoftext|>"]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FimFormat {
+    /// The token before the code before the hole.
+    pub prefix: String,
+    /// The token before the code after the hole.
+    pub suffix: String,
+    /// The token after which the model generates the code in the hole.
+    pub middle: String,
+    /// The sequences which end the generated code.
+    pub stop: Vec<String>,
+}
+impl FimFormat {
+    /// The format of the `CodeLlama` models.
+    pub fn code_llama() -> Self {
+        Self {
+            prefix: String::from("<PRE> "),
+            suffix: String::from(" <SUF>"),
+            middle: String::from(" <MID>"),
+            stop: vec![String::from("<EOT>")],
+        }
+    }
+
+    /// The format of the `DeepSeek-Coder` models.
+    pub fn deepseek_coder() -> Self {
+        Self {
+            prefix: String::from("<｜fim▁begin｜>"),
+            suffix: String::from("<｜fim▁hole｜>"),
+            middle: String::from("<｜fim▁end｜>"),
+            stop: vec![
+                String::from("<|EOT|>"),
+                String::from("<｜end▁of▁sentence｜>"),
+            ],
+        }
+    }
+
+    /// The format of the `StarCoder` models, which the `WizardCoder` models are fine-tuned from.
+    pub fn starcoder() -> Self {
+        Self {
+            prefix: String::from("<fim_prefix>"),
+            suffix: String::from("<fim_suffix>"),
+            middle: String::from("<fim_middle>"),
+            stop: vec![String::from("<|endoftext|>"), String::from("<file_sep>")],
+        }
+    }
+
+    /// Builds the prompt to fill the hole between `prefix` and `suffix`. The code is kept as is, since its whitespaces matter.
+    pub fn build(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{prefix_token}{prefix}{suffix_token}{suffix}{middle_token}",
+            prefix_token = self.prefix,
+            suffix_token = self.suffix,
+            middle_token = self.middle,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFIX: &str = "def add(a, b):\n    ";
+    const SUFFIX: &str = "\n\nprint(add(1, 2))\n";
+
+    #[test]
+    fn code_llama_layout() {
+        assert_eq!(
+            FimFormat::code_llama().build(PREFIX, SUFFIX),
+            "<PRE> def add(a, b):\n     <SUF>\n\nprint(add(1, 2))\n <MID>"
+        );
+    }
+
+    #[test]
+    fn deepseek_coder_layout() {
+        assert_eq!(
+            FimFormat::deepseek_coder().build(PREFIX, SUFFIX),
+            "<｜fim▁begin｜>def add(a, b):\n    <｜fim▁hole｜>\n\nprint(add(1, 2))\n<｜fim▁end｜>"
+        );
+    }
+
+    #[test]
+    fn starcoder_layout() {
+        assert_eq!(
+            FimFormat::starcoder().build(PREFIX, SUFFIX),
+            "<fim_prefix>def add(a, b):\n    <fim_suffix>\n\nprint(add(1, 2))\n<fim_middle>"
+        );
+    }
+
+    #[test]
+    fn read_custom_format() {
+        let format: FimFormat = toml::from_str(
+            "prefix = \"<fim_prefix>\"\nsuffix = \"<fim_suffix>\"\nmiddle = \"<fim_middle>\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            format.build(PREFIX, SUFFIX),
+            FimFormat::starcoder().build(PREFIX, SUFFIX)
+        );
+        assert!(format.stop.is_empty());
+    }
+}
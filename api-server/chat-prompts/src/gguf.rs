@@ -0,0 +1,479 @@
+//! A minimal reader of the metadata in the header of GGUF model files. Only the key-value pairs are read, the tensors are skipped.
+
+use crate::{
+    error::{PromptError, Result},
+    PromptTemplateType,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// The magic number at the beginning of GGUF files, i.e. `GGUF` in little-endian.
+const GGUF_MAGIC: u32 = 0x4655_4747;
+
+/// The value of a metadata key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+impl GgufValue {
+    /// Returns the string value, if the value is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an unsigned integer, if the value is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the array items, if the value is an array.
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(items) => Some(items.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+/// The metadata of a GGUF model file.
+#[derive(Debug, Clone, Default)]
+pub struct GgufMetadata {
+    values: HashMap<String, GgufValue>,
+}
+impl GgufMetadata {
+    /// Reads the metadata from the header of the GGUF file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path.as_ref()).map_err(|e| {
+            PromptError::InvalidModelFile(format!(
+                "Fail to open {path}: {msg}",
+                path = path.as_ref().display(),
+                msg = e
+            ))
+        })?;
+        Self::from_reader(BufReader::new(file))
+    }
+
+    /// Reads the metadata from the header of a GGUF file.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let reader = &mut reader;
+
+        if read_u32(reader)? != GGUF_MAGIC {
+            return Err(PromptError::InvalidModelFile(String::from(
+                "Not a GGUF file",
+            )));
+        }
+        let version = read_u32(reader)?;
+        if version < 2 {
+            return Err(PromptError::InvalidModelFile(format!(
+                "Unsupported GGUF version: {version}"
+            )));
+        }
+
+        // the number of tensors
+        let _ = read_u64(reader)?;
+        let n_kv = read_u64(reader)?;
+
+        let mut values = HashMap::new();
+        for _ in 0..n_kv {
+            let key = read_string(reader)?;
+            let ty = read_u32(reader)?;
+            let value = read_value(reader, ty)?;
+            values.insert(key, value);
+        }
+
+        Ok(Self { values })
+    }
+
+    /// Returns the value of the key.
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.values.get(key)
+    }
+
+    /// Returns the string value of the key.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(GgufValue::as_str)
+    }
+
+    /// The architecture of the model, i.e. `general.architecture`.
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str("general.architecture")
+    }
+
+    /// The name of the model, i.e. `general.name`.
+    pub fn name(&self) -> Option<&str> {
+        self.get_str("general.name")
+    }
+
+    /// The Jinja chat template of the model, i.e. `tokenizer.chat_template`.
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get_str("tokenizer.chat_template")
+    }
+
+    /// The text of the beginning-of-sequence token.
+    pub fn bos_token(&self) -> Option<&str> {
+        self.token("tokenizer.ggml.bos_token_id")
+    }
+
+    /// The text of the end-of-sequence token.
+    pub fn eos_token(&self) -> Option<&str> {
+        self.token("tokenizer.ggml.eos_token_id")
+    }
+
+    /// Guesses the prompt template of the model from its name, its chat template and its architecture. If none of the built-in templates matches, the chat template of the model is used if there is one.
+    pub fn prompt_template_type(&self) -> Option<PromptTemplateType> {
+        // the fine-tuned models are told by their names first, since they often share the architecture, and even the chat template, with their base models
+        let name = self
+            .name()
+            .unwrap_or_default()
+            .to_lowercase()
+            .replace([' ', '_'], "-");
+        let by_name = if name.contains("deepseek-coder") {
+            Some(PromptTemplateType::DeepseekCoder)
+        } else if name.contains("deepseek") && name.contains("chat") {
+            Some(PromptTemplateType::DeepseekChat)
+        } else if name.contains("codellama") || name.contains("code-llama") {
+            Some(PromptTemplateType::CodeLlama)
+        } else if name.contains("mistrallite") {
+            Some(PromptTemplateType::MistralLite)
+        } else if name.contains("openchat") {
+            Some(PromptTemplateType::OpenChat)
+        } else if name.contains("zephyr") {
+            Some(PromptTemplateType::Zephyr)
+        } else if name.contains("neural-chat") {
+            Some(PromptTemplateType::IntelNeural)
+        } else if name.contains("wizardcoder") {
+            Some(PromptTemplateType::WizardCoder)
+        } else if name.contains("belle") {
+            Some(PromptTemplateType::BelleLlama2Chat)
+        } else if name.contains("vicuna") && name.contains("1.1") {
+            Some(PromptTemplateType::Vicuna11Chat)
+        } else if name.contains("vicuna") {
+            Some(PromptTemplateType::VicunaChat)
+        } else if name.contains("baichuan2") || name.contains("baichuan-2") {
+            Some(PromptTemplateType::Baichuan2)
+        } else {
+            None
+        };
+        if by_name.is_some() {
+            return by_name;
+        }
+
+        // the markers of the chat template
+        if let Some(template) = self.chat_template() {
+            if template.contains("<|im_start|>") {
+                return Some(PromptTemplateType::ChatML);
+            } else if template.contains("<|end_of_turn|>") {
+                return Some(PromptTemplateType::OpenChat);
+            } else if template.contains("<|user|>") && template.contains("<|assistant|>") {
+                return Some(PromptTemplateType::Zephyr);
+            } else if template.contains("### User:") && template.contains("### Assistant:") {
+                return Some(PromptTemplateType::IntelNeural);
+            } else if template.contains("<<SYS>>") {
+                return Some(PromptTemplateType::Llama2Chat);
+            } else if template.contains("[INST]") {
+                return Some(PromptTemplateType::MistralInstruct);
+            }
+        }
+
+        // the base models
+        // `LLaMA v2` is the name given to the Llama 2 models by the conversion script of llama.cpp
+        if (name.contains("llama-2") && name.contains("chat")) || name == "llama-v2" {
+            return Some(PromptTemplateType::Llama2Chat);
+        } else if (name.contains("mistral") || name.contains("mixtral"))
+            && name.contains("instruct")
+        {
+            return Some(PromptTemplateType::MistralInstruct);
+        } else if self.architecture() == Some("baichuan") {
+            return Some(PromptTemplateType::Baichuan2);
+        }
+
+        self.chat_template().map(|_| PromptTemplateType::Jinja)
+    }
+
+    /// Looks up the text of the token whose id is the value of the key.
+    fn token(&self, id_key: &str) -> Option<&str> {
+        let id = self.get(id_key)?.as_u64()?;
+        let tokens = self.get("tokenizer.ggml.tokens")?.as_array()?;
+        tokens.get(usize::try_from(id).ok()?)?.as_str()
+    }
+}
+
+/// Resolves the `--prompt-template` of a model, which is either the name of a template or `auto`. `auto` detects the template from the metadata of the model file, and falls back to `llama-2-chat` if no model file is given. A named template is checked against the metadata if the model file is given.
+///
+/// Returns the type of the template, and a warning if the template may not fit the model.
+pub fn resolve_prompt_template(
+    prompt_template: &str,
+    metadata: Option<&GgufMetadata>,
+) -> Result<(PromptTemplateType, Option<String>)> {
+    let detected = metadata.and_then(GgufMetadata::prompt_template_type);
+
+    if prompt_template == "auto" {
+        return match (metadata, detected) {
+            (_, Some(template_ty)) => Ok((template_ty, None)),
+            (Some(metadata), None) => Err(PromptError::UnknownPromptTemplateType(format!(
+                "Fail to detect the prompt template of {name}. Please set it with `--prompt-template`",
+                name = metadata.name().unwrap_or("the model")
+            ))),
+            (None, None) => Ok((
+                PromptTemplateType::Llama2Chat,
+                Some(String::from("No model file is given to detect the prompt template, so `llama-2-chat` is used. Set `--model-file` or `--prompt-template` to choose the right one")),
+            )),
+        };
+    }
+
+    let template_ty = prompt_template.parse::<PromptTemplateType>()?;
+    let warning = match detected {
+        // the chat template of the model may be written in any format
+        Some(detected)
+            if detected != template_ty
+                && detected != PromptTemplateType::Jinja
+                && template_ty != PromptTemplateType::Jinja =>
+        {
+            Some(format!(
+                "The prompt template `{template_ty}` does not match the model, which looks like `{detected}`"
+            ))
+        }
+        _ => None,
+    };
+    Ok((template_ty, warning))
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf).map_err(|e| {
+        PromptError::InvalidModelFile(format!("Fail to read the GGUF header: {msg}", msg = e))
+    })?;
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u64(reader)?;
+    let mut buf = vec![];
+    reader.take(len).read_to_end(&mut buf).map_err(|e| {
+        PromptError::InvalidModelFile(format!("Fail to read the GGUF header: {msg}", msg = e))
+    })?;
+    if buf.len() as u64 != len {
+        return Err(PromptError::InvalidModelFile(String::from(
+            "Unexpected end of the GGUF header",
+        )));
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn read_value(reader: &mut impl Read, ty: u32) -> Result<GgufValue> {
+    let value = match ty {
+        0 => GgufValue::U8(u8::from_le_bytes(read_bytes(reader)?)),
+        1 => GgufValue::I8(i8::from_le_bytes(read_bytes(reader)?)),
+        2 => GgufValue::U16(u16::from_le_bytes(read_bytes(reader)?)),
+        3 => GgufValue::I16(i16::from_le_bytes(read_bytes(reader)?)),
+        4 => GgufValue::U32(read_u32(reader)?),
+        5 => GgufValue::I32(i32::from_le_bytes(read_bytes(reader)?)),
+        6 => GgufValue::F32(f32::from_le_bytes(read_bytes(reader)?)),
+        7 => GgufValue::Bool(read_bytes::<1>(reader)?[0] != 0),
+        8 => GgufValue::String(read_string(reader)?),
+        9 => {
+            let item_ty = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            let mut items = vec![];
+            for _ in 0..len {
+                items.push(read_value(reader, item_ty)?);
+            }
+            GgufValue::Array(items)
+        }
+        10 => GgufValue::U64(read_u64(reader)?),
+        11 => GgufValue::I64(i64::from_le_bytes(read_bytes(reader)?)),
+        12 => GgufValue::F64(f64::from_le_bytes(read_bytes(reader)?)),
+        _ => {
+            return Err(PromptError::InvalidModelFile(format!(
+                "Unknown GGUF value type: {ty}"
+            )))
+        }
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}";
+
+    fn string(s: &str) -> Vec<u8> {
+        [&(s.len() as u64).to_le_bytes(), s.as_bytes()].concat()
+    }
+
+    /// Builds the header of a GGUF file of the given version, with the name, the chat template and the tokens of a model.
+    fn header(version: u32) -> Vec<u8> {
+        let tokens = [
+            &8u32.to_le_bytes()[..],
+            &3u64.to_le_bytes(),
+            &string("<unk>"),
+            &string("<s>"),
+            &string("<|im_end|>"),
+        ]
+        .concat();
+        let values = [
+            ("general.name", 8u32, string("OpenHermes 2.5 Mistral 7B")),
+            ("tokenizer.chat_template", 8, string(CHAT_TEMPLATE)),
+            ("tokenizer.ggml.tokens", 9, tokens),
+            (
+                "tokenizer.ggml.bos_token_id",
+                4,
+                1u32.to_le_bytes().to_vec(),
+            ),
+            (
+                "tokenizer.ggml.eos_token_id",
+                4,
+                2u32.to_le_bytes().to_vec(),
+            ),
+        ];
+
+        // the magic number, the version, and the number of tensors and key-value pairs
+        let mut header = [
+            &b"GGUF"[..],
+            &version.to_le_bytes(),
+            &291u64.to_le_bytes(),
+            &(values.len() as u64).to_le_bytes(),
+        ]
+        .concat();
+        for (key, ty, value) in values {
+            header.extend(string(key));
+            header.extend(ty.to_le_bytes());
+            header.extend(value);
+        }
+        header
+    }
+
+    #[test]
+    fn read_header() {
+        for version in [2, 3] {
+            let metadata = GgufMetadata::from_reader(header(version).as_slice()).unwrap();
+
+            assert_eq!(metadata.name(), Some("OpenHermes 2.5 Mistral 7B"));
+            assert_eq!(metadata.chat_template(), Some(CHAT_TEMPLATE));
+            assert_eq!(metadata.bos_token(), Some("<s>"));
+            assert_eq!(metadata.eos_token(), Some("<|im_end|>"));
+        }
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        let mut file = header(3);
+        file[..4].copy_from_slice(b"GGML");
+        assert!(GgufMetadata::from_reader(file.as_slice()).is_err());
+
+        // the counts of version 1 are 32-bit
+        assert!(GgufMetadata::from_reader(header(1).as_slice()).is_err());
+
+        let file = header(3);
+        assert!(GgufMetadata::from_reader(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn detect_prompt_template() {
+        let mut metadata = GgufMetadata::default();
+        assert_eq!(metadata.prompt_template_type(), None);
+
+        metadata.values.insert(
+            String::from("tokenizer.chat_template"),
+            GgufValue::String(String::from("{{ bos_token }}{% for message in messages %}")),
+        );
+        assert_eq!(
+            metadata.prompt_template_type(),
+            Some(PromptTemplateType::Jinja)
+        );
+
+        metadata.values.insert(
+            String::from("tokenizer.chat_template"),
+            GgufValue::String(String::from(CHAT_TEMPLATE)),
+        );
+        assert_eq!(
+            metadata.prompt_template_type(),
+            Some(PromptTemplateType::ChatML)
+        );
+
+        // the name of a fine-tuned model wins over its chat template
+        metadata.values.insert(
+            String::from("general.name"),
+            GgufValue::String(String::from("Zephyr 7B Beta")),
+        );
+        assert_eq!(
+            metadata.prompt_template_type(),
+            Some(PromptTemplateType::Zephyr)
+        );
+    }
+
+    #[test]
+    fn resolve_template_of_model() {
+        let metadata = GgufMetadata::from_reader(header(3).as_slice()).unwrap();
+
+        assert_eq!(
+            resolve_prompt_template("auto", Some(&metadata)).unwrap(),
+            (PromptTemplateType::ChatML, None)
+        );
+        assert_eq!(
+            resolve_prompt_template("chatml", Some(&metadata)).unwrap(),
+            (PromptTemplateType::ChatML, None)
+        );
+        // a named template is used even if it does not match the model
+        let (template_ty, warning) =
+            resolve_prompt_template("llama-2-chat", Some(&metadata)).unwrap();
+        assert_eq!(template_ty, PromptTemplateType::Llama2Chat);
+        assert!(warning.is_some());
+
+        assert!(resolve_prompt_template("auto", Some(&GgufMetadata::default())).is_err());
+        assert!(matches!(
+            resolve_prompt_template("chat-ml", Some(&metadata)),
+            Err(PromptError::UnknownPromptTemplateType(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_template_without_model() {
+        let (template_ty, warning) = resolve_prompt_template("auto", None).unwrap();
+        assert_eq!(template_ty, PromptTemplateType::Llama2Chat);
+        assert!(warning.is_some());
+
+        assert_eq!(
+            resolve_prompt_template("zephyr", None).unwrap(),
+            (PromptTemplateType::Zephyr, None)
+        );
+    }
+}
@@ -1,5 +1,12 @@
+pub mod budget;
 pub mod chat;
 pub mod error;
+pub mod fim;
+pub mod gguf;
+pub mod output;
+pub mod stop;
+pub mod tools;
+pub mod utf8;
 
 use std::str::FromStr;
 
@@ -20,6 +27,10 @@ pub enum PromptTemplateType {
     IntelNeural,
     DeepseekChat,
     DeepseekCoder,
+    /// User-defined template loaded from a file. It has no name to be parsed from.
+    Custom,
+    /// Hugging Face chat template in Jinja, loaded from a template file or a model file.
+    Jinja,
 }
 impl FromStr for PromptTemplateType {
     type Err = error::PromptError;
@@ -42,6 +53,7 @@ impl FromStr for PromptTemplateType {
             "intel-neural" => Ok(PromptTemplateType::IntelNeural),
             "deepseek-chat" => Ok(PromptTemplateType::DeepseekChat),
             "deepseek-coder" => Ok(PromptTemplateType::DeepseekCoder),
+            "jinja" => Ok(PromptTemplateType::Jinja),
             _ => Err(error::PromptError::UnknownPromptTemplateType(
                 template.to_string(),
             )),
@@ -66,6 +78,8 @@ impl std::fmt::Display for PromptTemplateType {
             PromptTemplateType::IntelNeural => write!(f, "intel-neural"),
             PromptTemplateType::DeepseekChat => write!(f, "deepseek-chat"),
             PromptTemplateType::DeepseekCoder => write!(f, "deepseek-coder"),
+            PromptTemplateType::Custom => write!(f, "custom"),
+            PromptTemplateType::Jinja => write!(f, "jinja"),
         }
     }
 }
@@ -0,0 +1,58 @@
+//! Retrieves the outputs of the model, whose sizes are not known in advance, into buffers which grow until the whole output fits.
+
+use thiserror::Error;
+
+/// The size of the buffer an output is first retrieved into.
+pub const INITIAL_OUTPUT_SIZE: usize = 1 << 12;
+/// The maximum size of an output, which bounds the growth of the buffer.
+pub const MAX_OUTPUT_SIZE: usize = 1 << 28;
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum OutputError {
+    #[error("The output exceeds the maximum size of {0} bytes.")]
+    TooLarge(usize),
+    #[error("Fail to get the output: {0}")]
+    Backend(String),
+}
+
+/// Retrieves an output with `get_output`, which writes the output into the given buffer and returns its size. The errors for which `is_too_small` holds mean the backend rejects the buffer as too small.
+///
+/// An output which fills the whole buffer may be cut, so it is retrieved again into a buffer at least twice as large, or as large as the size reported by the backend, until the output leaves room in the buffer. The output is never cut silently: it is an error if it does not fit into `MAX_OUTPUT_SIZE` bytes.
+pub fn read_output<E: ToString>(
+    mut get_output: impl FnMut(&mut [u8]) -> Result<usize, E>,
+    is_too_small: impl Fn(&E) -> bool,
+) -> Result<Vec<u8>, OutputError> {
+    let mut size = INITIAL_OUTPUT_SIZE;
+    loop {
+        let mut buffer = vec![0u8; size];
+        let wanted = match get_output(&mut buffer) {
+            Ok(len) if len < size => {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            Ok(len) => len + 1,
+            Err(e) if is_too_small(&e) => 0,
+            Err(e) => return Err(OutputError::Backend(e.to_string())),
+        };
+
+        if size >= MAX_OUTPUT_SIZE {
+            return Err(OutputError::TooLarge(MAX_OUTPUT_SIZE));
+        }
+        size = std::cmp::min(std::cmp::max(wanted, size * 2), MAX_OUTPUT_SIZE);
+    }
+}
+
+/// Retrieves an output of a wasi-nn execution context with `read_output`, e.g. `read_wasi_nn_output!(|buffer| context.get_output(0, buffer))`. The backend rejects a buffer which is too small with `BackendError::TooLarge`.
+///
+/// The `wasi_nn` paths are resolved in the calling crate, since the binaries depend on different branches of `wasi-nn`, whose errors are distinct types.
+#[macro_export]
+macro_rules! read_wasi_nn_output {
+    ($get_output:expr) => {
+        $crate::output::read_output($get_output, |e: &wasi_nn::Error| {
+            matches!(
+                e,
+                wasi_nn::Error::BackendError(wasi_nn::BackendError::TooLarge)
+            )
+        })
+    };
+}
@@ -0,0 +1,146 @@
+//! Finds the stop sequences in the text streamed from the model, which may be split across several tokens.
+
+/// Matches the stop sequences against the text generated so far.
+///
+/// The text is pushed piece by piece, e.g. token by token, and the matcher returns the part of it which can be shown to the user. The end of the text which may be the beginning of a stop sequence is held back until the following text tells whether the stop sequence is completed. Once a stop sequence is found, the text before it is returned and the rest is dropped.
+#[derive(Debug, Default, Clone)]
+pub struct StopMatcher {
+    stops: Vec<String>,
+    /// The text held back, which is a prefix of some stop sequence.
+    pending: String,
+    stopped: bool,
+}
+impl StopMatcher {
+    /// Creates a matcher of the given stop sequences. The empty ones are ignored.
+    pub fn new(stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut matcher = Self::default();
+        for stop in stops {
+            let stop = stop.into();
+            if !stop.is_empty() && !matcher.stops.contains(&stop) {
+                matcher.stops.push(stop);
+            }
+        }
+        matcher
+    }
+
+    /// Pushes the next piece of the generated text, and returns the text which is sure not to be a part of any stop sequence. Nothing is returned once a stop sequence has been found.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(text);
+
+        // the text ends at the earliest stop sequence
+        if let Some(end) = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.stopped = true;
+            self.pending.truncate(end);
+            return std::mem::take(&mut self.pending);
+        }
+
+        // hold back the longest end of the text which begins a stop sequence
+        let held = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                stop.char_indices()
+                    .map(|(i, _)| i)
+                    .skip(1)
+                    .filter(|&len| self.pending.ends_with(&stop[..len]))
+                    .max()
+            })
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Returns the text held back when the generation ends without completing a stop sequence.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Whether a stop sequence has been found, so the generation should stop.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes the pieces one by one, and returns what each push returns.
+    fn push_all(matcher: &mut StopMatcher, pieces: &[&str]) -> Vec<String> {
+        pieces.iter().map(|piece| matcher.push(piece)).collect()
+    }
+
+    #[test]
+    fn stop_split_across_two_tokens() {
+        let mut matcher = StopMatcher::new(["<|im_end|>"]);
+        let shown = push_all(&mut matcher, &["Hello", " world<|im", "_end|>", " more"]);
+
+        assert_eq!(shown, ["Hello", " world", "", ""]);
+        assert!(matcher.stopped());
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn stop_split_across_three_tokens() {
+        let mut matcher = StopMatcher::new(["<|im_end|>"]);
+        let shown = push_all(&mut matcher, &["Paris.<|im", "_en", "d|>\nuser"]);
+
+        assert_eq!(shown, ["Paris.", "", ""]);
+        assert!(matcher.stopped());
+    }
+
+    #[test]
+    fn earliest_of_overlapping_stops() {
+        let mut matcher = StopMatcher::new(["bcd", "abc"]);
+        let shown = push_all(&mut matcher, &["xab", "cd"]);
+
+        // "ab" may begin "abc", and "b" may begin "bcd"; the text ends at "abc", which begins first
+        assert_eq!(shown, ["x", ""]);
+        assert!(matcher.stopped());
+    }
+
+    #[test]
+    fn stop_overlapping_itself() {
+        let mut matcher = StopMatcher::new(["aab"]);
+        let shown = push_all(&mut matcher, &["aa", "a", "b"]);
+
+        // after "aaa", only the last "aa" may begin the stop sequence
+        assert_eq!(shown, ["", "a", ""]);
+        assert!(matcher.stopped());
+    }
+
+    #[test]
+    fn release_held_text_which_does_not_match() {
+        let mut matcher = StopMatcher::new(["</s>"]);
+        let shown = push_all(&mut matcher, &["a <", "b"]);
+
+        assert_eq!(shown, ["a ", "<b"]);
+        assert!(!matcher.stopped());
+    }
+
+    #[test]
+    fn flush_held_text_at_the_end() {
+        let mut matcher = StopMatcher::new(["</s>"]);
+        let shown = push_all(&mut matcher, &["Done", "</"]);
+
+        assert_eq!(shown, ["Done", ""]);
+        assert!(!matcher.stopped());
+        assert_eq!(matcher.flush(), "</");
+    }
+
+    #[test]
+    fn ignore_empty_stops() {
+        let mut matcher = StopMatcher::new(["", "\n\n"]);
+        assert_eq!(push_all(&mut matcher, &["a", "b"]), ["a", "b"]);
+        assert!(!matcher.stopped());
+    }
+}
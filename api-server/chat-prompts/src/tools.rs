@@ -0,0 +1,173 @@
+//! Helpers for the prompt templates which let the models call tools.
+
+use crate::error::{PromptError, Result};
+use endpoints::chat::{ChatCompletionRequestMessage, Tool, ToolCall, ToolCallFunction};
+use serde_json::Value;
+
+/// Describes the tools in the format of the Hermes 2 Pro models, which is appended to the system prompt. The models call the tools with JSON objects enclosed by `<tool_call></tool_call>` tags.
+pub fn hermes_tool_prompt(tools: &[Tool]) -> Result<String> {
+    let mut definitions = String::new();
+    for tool in tools {
+        let definition =
+            serde_json::to_string(tool).map_err(|e| PromptError::InvalidTool(e.to_string()))?;
+        definitions.push_str(&definition);
+        definitions.push('\n');
+    }
+
+    Ok(format!(
+        "You are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. Don't make assumptions about what values to plug into functions. Here are the available tools: <tools>\n{definitions}</tools>\nFor each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags as follows:\n<tool_call>\n{{\"name\": <function-name>, \"arguments\": <args-dict>}}\n</tool_call>"
+    ))
+}
+
+/// Writes the tool calls of an assistant message in the format of the Hermes 2 Pro models.
+pub fn hermes_tool_calls(tool_calls: &[ToolCall]) -> String {
+    tool_calls
+        .iter()
+        .map(|tool_call| {
+            // the arguments are a JSON string, which is embedded as an object if it is valid
+            let arguments = serde_json::from_str::<Value>(&tool_call.function.arguments)
+                .unwrap_or_else(|_| Value::String(tool_call.function.arguments.clone()));
+            let call = serde_json::json!({
+                "name": tool_call.function.name,
+                "arguments": arguments,
+            });
+            format!("<tool_call>\n{call}\n</tool_call>")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes the result of a tool or function call as the content of a user turn, for the templates which have no role for the results. The result is prefixed with the name of the function if the message has one, so that the model tells it from the words of the user.
+pub fn tool_result_content(message: &ChatCompletionRequestMessage) -> String {
+    let content = message.content_text();
+    match &message.name {
+        Some(name) => format!(
+            "Result of the function `{name}`:\n{content}",
+            content = content.trim()
+        ),
+        None => format!(
+            "Result of the tool call:\n{content}",
+            content = content.trim()
+        ),
+    }
+}
+
+/// Extracts the tool calls from the answer of a model. The calls are written either in the format of the Hermes 2 Pro models, i.e. `<tool_call>{"name": ..., "arguments": ...}</tool_call>`, or in the format of the Mistral models, i.e. `[TOOL_CALLS] [{"name": ..., "arguments": ...}]`.
+///
+/// Returns the text out of the tool calls, and the tool calls. Malformed tool calls are left in the text.
+pub fn parse_tool_calls(output: &str) -> (String, Vec<ToolCallFunction>) {
+    const OPEN_TAG: &str = "<tool_call>";
+    const CLOSE_TAG: &str = "</tool_call>";
+
+    let mut text = String::new();
+    let mut calls = vec![];
+    let mut rest = output;
+    while let Some(start) = rest.find(OPEN_TAG) {
+        let body_start = start + OPEN_TAG.len();
+        // the closing tag of the last call may be cut by the stop sequences
+        let (body, next) = match rest[body_start..].find(CLOSE_TAG) {
+            Some(len) => (
+                &rest[body_start..body_start + len],
+                &rest[body_start + len + CLOSE_TAG.len()..],
+            ),
+            None => (&rest[body_start..], ""),
+        };
+
+        match serde_json::from_str::<Value>(body.trim())
+            .ok()
+            .as_ref()
+            .and_then(to_tool_call)
+        {
+            Some(call) => {
+                text.push_str(&rest[..start]);
+                calls.push(call);
+            }
+            None => text.push_str(&rest[..rest.len() - next.len()]),
+        }
+        rest = next;
+    }
+    text.push_str(rest);
+
+    if calls.is_empty() {
+        if let Some((before, after)) = text.split_once("[TOOL_CALLS]") {
+            let parsed = match serde_json::from_str::<Value>(after.trim()) {
+                Ok(Value::Array(items)) => items.iter().map(to_tool_call).collect(),
+                Ok(item) => to_tool_call(&item).map(|call| vec![call]),
+                Err(_) => None,
+            };
+            if let Some(parsed) = parsed {
+                calls = parsed;
+                text = before.to_owned();
+            }
+        }
+    }
+
+    (text.trim().to_owned(), calls)
+}
+
+/// Converts a JSON object with the `name` and `arguments` fields to a tool call.
+fn to_tool_call(value: &Value) -> Option<ToolCallFunction> {
+    let name = value.get("name")?.as_str()?;
+    let arguments = match value.get("arguments") {
+        Some(Value::String(arguments)) => arguments.clone(),
+        Some(arguments) => arguments.to_string(),
+        None => String::from("{}"),
+    };
+
+    Some(ToolCallFunction {
+        name: name.to_owned(),
+        arguments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hermes_tool_calls() {
+        let (text, calls) = parse_tool_calls(
+            "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>\n<tool_call>\n{\"name\": \"get_time\"}\n</tool_call>",
+        );
+
+        assert_eq!(text, "Let me check.");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].arguments, "{\"city\":\"Paris\"}");
+        assert_eq!(calls[1].name, "get_time");
+        assert_eq!(calls[1].arguments, "{}");
+    }
+
+    #[test]
+    fn parse_mistral_tool_calls() {
+        let (text, calls) = parse_tool_calls(
+            "[TOOL_CALLS] [{\"name\": \"get_weather\", \"arguments\": \"{\\\"city\\\": \\\"Paris\\\"}\"}]",
+        );
+
+        assert_eq!(text, "");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].arguments, "{\"city\": \"Paris\"}");
+    }
+
+    #[test]
+    fn keep_malformed_tool_calls_in_text() {
+        let output = "<tool_call>\n{\"arguments\": {}}\n</tool_call>";
+        let (text, calls) = parse_tool_calls(output);
+        assert_eq!(text, output);
+        assert!(calls.is_empty());
+
+        let (text, calls) = parse_tool_calls("[TOOL_CALLS] not json");
+        assert_eq!(text, "[TOOL_CALLS] not json");
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn parse_call_cut_by_stop_sequence() {
+        let (text, calls) =
+            parse_tool_calls("<tool_call>\n{\"name\": \"get_time\", \"arguments\": {}}\n");
+
+        assert_eq!(text, "");
+        assert_eq!(calls[0].name, "get_time");
+    }
+}
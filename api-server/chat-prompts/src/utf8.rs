@@ -0,0 +1,108 @@
+//! Decodes the text streamed from the model, whose multi-byte UTF-8 characters may be split across several tokens.
+
+/// Decodes the bytes of the generated text into UTF-8 text.
+///
+/// The bytes are pushed piece by piece, e.g. token by token, and the decoder returns the complete characters only. The bytes at the end which begin a multi-byte character are held back until the following bytes complete it. Invalid bytes are replaced with `U+FFFD REPLACEMENT CHARACTER`, as `String::from_utf8_lossy` does.
+#[derive(Debug, Default, Clone)]
+pub struct Utf8Decoder {
+    /// The bytes held back, which begin an incomplete character.
+    pending: Vec<u8>,
+}
+impl Utf8Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes the next bytes of the generated text, and returns the text of the characters completed so far.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        loop {
+            let (valid_up_to, error_len) = match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(e) => (e.valid_up_to(), e.error_len()),
+            };
+            text.push_str(&String::from_utf8_lossy(&self.pending[..valid_up_to]));
+
+            match error_len {
+                // the bytes at the end may be completed by the following ones
+                None => {
+                    self.pending.drain(..valid_up_to);
+                    return text;
+                }
+                // the invalid bytes are replaced, and the bytes after them are decoded
+                Some(len) => {
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    self.pending.drain(..valid_up_to + len);
+                }
+            }
+        }
+    }
+
+    /// Returns the text of the bytes held back when the generation ends. The incomplete character is replaced, since no more bytes complete it.
+    pub fn flush(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the bytes pushed in the given pieces, including the flushed ones.
+    fn decode(pieces: &[&[u8]]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut text: String = pieces.iter().map(|piece| decoder.push(piece)).collect();
+        text.push_str(&decoder.flush());
+        text
+    }
+
+    #[test]
+    fn characters_split_at_every_byte() {
+        // 2, 3 and 4 bytes long
+        for text in ["aéb", "a€b", "a😀b"] {
+            let bytes = text.as_bytes();
+            for split in 1..bytes.len() {
+                let mut decoder = Utf8Decoder::new();
+                let head = decoder.push(&bytes[..split]);
+                let tail = decoder.push(&bytes[split..]);
+
+                // the first part never ends with a replaced character
+                assert!(text.starts_with(&head), "{text:?} split at {split}");
+                assert_eq!(format!("{head}{tail}"), text, "{text:?} split at {split}");
+                assert_eq!(decoder.flush(), "");
+            }
+        }
+    }
+
+    #[test]
+    fn character_pushed_byte_by_byte() {
+        let mut decoder = Utf8Decoder::new();
+        let pieces: Vec<String> = "😀".bytes().map(|byte| decoder.push(&[byte])).collect();
+
+        assert_eq!(pieces, ["", "", "", "😀"]);
+    }
+
+    #[test]
+    fn replace_invalid_bytes() {
+        assert_eq!(decode(&[b"a\xffb"]), "a\u{FFFD}b");
+        assert_eq!(decode(&[b"a\xe2\x82", b"b"]), "a\u{FFFD}b");
+        assert_eq!(decode(&[b"\x80\x80"]), "\u{FFFD}\u{FFFD}");
+    }
+
+    #[test]
+    fn replace_incomplete_character_at_the_end() {
+        let mut decoder = Utf8Decoder::new();
+
+        assert_eq!(decoder.push(b"a\xf0\x9f\x98"), "a");
+        assert_eq!(decoder.flush(), "\u{FFFD}");
+        assert_eq!(decoder.flush(), "");
+    }
+}
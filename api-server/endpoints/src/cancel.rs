@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Cancels a running completion or chat completion.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CancelRequest {
+    /// The id of the completion, which is the `id` of the streamed chunks, or the `X-Completion-Id` header of the request.
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CancelResponse {
+    /// The id of the cancelled completion.
+    pub id: String,
+    /// The object type, which is always "completion.cancelled".
+    pub object: String,
+}
@@ -1,6 +1,6 @@
 use crate::common::{FinishReason, Usage};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
 pub struct ChatCompletionRequestBuilder {
     req: ChatCompletionRequest,
@@ -32,6 +32,11 @@ impl ChatCompletionRequestBuilder {
                 user: None,
                 functions: None,
                 function_call: None,
+                tools: None,
+                tool_choice: None,
+                response_format: None,
+                logprobs: None,
+                top_logprobs: None,
             },
         }
     }
@@ -110,6 +115,21 @@ impl ChatCompletionRequestBuilder {
         self
     }
 
+    pub fn with_tools(mut self, tools: Vec<Tool>) -> Self {
+        self.req.tools = Some(tools);
+        self
+    }
+
+    pub fn with_tool_choice(mut self, tool_choice: ToolChoice) -> Self {
+        self.req.tool_choice = Some(tool_choice);
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.req.response_format = Some(response_format);
+        self
+    }
+
     pub fn build(self) -> ChatCompletionRequest {
         self.req
     }
@@ -138,7 +158,7 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f32>,
     /// How many chat completion choices to generate for each input message.
     /// Defaults to 1.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", alias = "n")]
     pub n_choice: Option<i32>,
     /// Whether to stream the results as they are generated. Useful for chatbots.
     /// Defaults to false.
@@ -177,15 +197,60 @@ pub struct ChatCompletionRequest {
     /// Controls how the model responds to function calls. "none" means the model does not call a function, and responds to the end-user. "auto" means the model can pick between an end-user or calling a function. Specifying a particular function via `{"name":\ "my_function"}` forces the model to call that function. "none" is the default when no functions are present. "auto" is the default if functions are present.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<String>,
+    /// A list of tools the model may call. Currently, only functions are supported as a tool. Supersedes `functions`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    /// Controls which (if any) tool is called by the model. Supersedes `function_call`. `none` is the default when no tools are present. `auto` is the default if tools are present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// The format that the model must output. Setting to `{"type": "json_object"}` or `{"type": "json_schema", "json_schema": {...}}` guarantees the message the model generates is valid JSON.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// Whether to return the log probabilities of the output tokens in the `content` of `logprobs` of each choice.
+    /// Defaults to false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// An integer between 0 and 20 specifying the number of the most likely tokens to return at each token position, each with an associated log probability. `logprobs` must be set to true if this parameter is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The format that the model must output.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Plain text, which is the default.
+    Text,
+    /// A JSON object.
+    JsonObject,
+    /// A JSON value matching the given schema.
+    JsonSchema {
+        json_schema: ResponseFormatJsonSchema,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseFormatJsonSchema {
+    /// The name of the response format.
+    pub name: String,
+    /// A description of what the response format is for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The schema of the response.
+    pub schema: JSONSchemaDefine,
+    /// Whether to follow the schema strictly. The output is always constrained by the schema, so the flag is ignored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatCompletionRequestMessage {
-    /// The role of the messages author. One of `system`, `user`, `assistant`, or `function`.
+    /// The role of the messages author. One of `system`, `user`, `assistant`, `tool` or `function`.
     pub role: ChatCompletionRole,
 
-    /// The contents of the message. `content` is required for all messages, and may be empty for assistant messages with function calls.
-    pub content: String,
+    /// The contents of the message, either a string or an array of content parts. `content` is required for all messages except assistant messages with tool calls or function calls, where it may be `null`.
+    #[serde(default)]
+    pub content: Option<ChatCompletionRequestMessageContent>,
 
     /// Only avaiable for OpenAI API. The name of the author of this message. `name` is required if role is `function`, and it should be the name of the function whose response is in the `content`. May contain a-z, A-Z, 0-9, and underscores, with a maximum length of 64 characters.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -194,18 +259,96 @@ pub struct ChatCompletionRequestMessage {
     /// Only available for OpenAI API. The name and arguments of a function that should be called, as generated by the model.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<ChatMessageFunctionCall>,
+
+    /// The tool calls generated by the model, if the role is `assistant`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+
+    /// The id of the tool call which this message responds to. `tool_call_id` is required if role is `tool`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 impl ChatCompletionRequestMessage {
     pub fn new(role: ChatCompletionRole, content: impl Into<String>) -> Self {
         Self {
             role,
-            content: content.into(),
+            content: Some(ChatCompletionRequestMessageContent::Text(content.into())),
             name: None,
             function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// The text of the message, which is empty if the message has no content.
+    pub fn content_text(&self) -> Cow<'_, str> {
+        match &self.content {
+            Some(content) => content.text(),
+            None => Cow::Borrowed(""),
         }
     }
 }
 
+/// The contents of a message.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ChatCompletionRequestMessageContent {
+    /// The text contents of the message.
+    Text(String),
+    /// An array of content parts with defined types.
+    Parts(Vec<ChatCompletionContentPart>),
+}
+impl ChatCompletionRequestMessageContent {
+    /// The text of the contents. The text parts are joined with newlines, and the other parts are left out since they have no text.
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            ChatCompletionRequestMessageContent::Text(text) => Cow::Borrowed(text.as_str()),
+            ChatCompletionRequestMessageContent::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ChatCompletionContentPart::Text { text } => Some(text.as_str()),
+                        ChatCompletionContentPart::Refusal { refusal } => Some(refusal.as_str()),
+                        ChatCompletionContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+}
+impl From<String> for ChatCompletionRequestMessageContent {
+    fn from(text: String) -> Self {
+        ChatCompletionRequestMessageContent::Text(text)
+    }
+}
+impl From<&str> for ChatCompletionRequestMessageContent {
+    fn from(text: &str) -> Self {
+        ChatCompletionRequestMessageContent::Text(text.to_owned())
+    }
+}
+
+/// A part of the contents of a message.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionContentPart {
+    /// A text part.
+    Text { text: String },
+    /// An image part, which is only understood by the multimodal models.
+    ImageUrl { image_url: ImageUrl },
+    /// The refusal of the model, in the assistant messages.
+    Refusal { refusal: String },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ImageUrl {
+    /// Either a URL of the image or the base64 encoded image data.
+    pub url: String,
+    /// Specifies the detail level of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum ChatCompletionRequestSampling {
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the output more random, while lower values like 0.2 will make it more focused and deterministic.
@@ -218,24 +361,83 @@ pub enum ChatCompletionRequestSampling {
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum ChatCompletionRole {
+    /// The instructions for the model. The `developer` role of the newer OpenAI models is read as `system`, so it is lost once parsed, and a `developer` message is serialized back with the `system` role.
+    #[serde(alias = "developer")]
     System,
     User,
     Assistant,
+    /// The result of a tool call.
+    Tool,
+    /// The result of a function call. Deprecated in favor of `Tool`.
     Function,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatCompletionRequestFunction {
-    name: String,
+    /// The name of the function to be called. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
+    pub name: String,
+    /// A description of what the function does, used by the model to choose when and how to call the function.
     #[serde(skip_serializing_if = "Option::is_none")]
-    description: Option<String>,
-    parameters: ChatCompletionRequestFunctionParameters,
+    pub description: Option<String>,
+    pub parameters: ChatCompletionRequestFunctionParameters,
+}
+
+/// A tool the model may call.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Tool {
+    /// The type of the tool. Currently, only `function` is supported.
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub function: ChatCompletionRequestFunction,
+}
+impl Tool {
+    pub fn new(function: ChatCompletionRequestFunction) -> Self {
+        Self {
+            ty: String::from("function"),
+            function,
+        }
+    }
+}
+
+/// Controls which (if any) tool is called by the model.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    /// One of `none`, `auto` or `required`.
+    Mode(ToolChoiceMode),
+    /// Forces the model to call the given tool, e.g. `{"type": "function", "function": {"name": "my_function"}}`.
+    Tool(ToolChoiceTool),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    /// The model does not call any tool, and generates a message instead.
+    None,
+    /// The model can pick between generating a message or calling tools.
+    Auto,
+    /// The model must call one or more tools.
+    Required,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ToolChoiceTool {
+    /// The type of the tool. Currently, only `function` is supported.
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub function: ToolChoiceFunction,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ToolChoiceFunction {
+    /// The name of the function to call.
+    pub name: String,
 }
 
 /// The parameters the functions accepts, described as a JSON Schema object. See the [guide](https://platform.openai.com/docs/guides/gpt/function-calling) for examples, and the [JSON Schema reference](https://json-schema.org/understanding-json-schema/) for documentation about the format.
 ///
 /// To describe a function that accepts no parameters, provide the value `{"type": "object", "properties": {}}`.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatCompletionRequestFunctionParameters {
     #[serde(rename = "type")]
     pub schema_type: JSONSchemaType,
@@ -245,24 +447,25 @@ pub struct ChatCompletionRequestFunctionParameters {
     pub required: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum JSONSchemaType {
     Object,
     Number,
+    Integer,
     String,
     Array,
     Null,
     Boolean,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JSONSchemaDefine {
     #[serde(rename = "type")]
     pub schema_type: Option<JSONSchemaType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
     pub enum_values: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub properties: Option<HashMap<String, Box<JSONSchemaDefine>>>,
@@ -287,6 +490,9 @@ pub struct ChatCompletionObject {
     pub choices: Vec<ChatCompletionObjectChoice>,
     /// Usage statistics for the completion request.
     pub usage: Usage,
+    /// The number of the oldest messages dropped to fit the conversation into the context window of the model. Only present if any message is dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated_messages: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -295,7 +501,7 @@ pub struct ChatCompletionObjectChoice {
     pub index: u32,
     /// A chat completion message generated by the model.
     pub message: ChatCompletionObjectMessage,
-    /// The reason the model stopped generating tokens. This will be `stop` if the model hit a natural stop point or a provided stop sequence, `length` if the maximum number of tokens specified in the request was reached, or `function_call` if the model called a function.
+    /// The reason the model stopped generating tokens. This will be `stop` if the model hit a natural stop point or a provided stop sequence, `length` if the maximum number of tokens specified in the request was reached, `tool_calls` if the model called a tool, or `function_call` if the model called a function.
     pub finish_reason: FinishReason,
 }
 
@@ -303,15 +509,18 @@ pub struct ChatCompletionObjectChoice {
 pub struct ChatCompletionObjectMessage {
     /// The role of the author of this message.
     pub role: ChatCompletionRole,
-    /// The contents of the message.
-    pub content: String,
+    /// The contents of the message, which is `null` if the model only calls tools or functions.
+    pub content: Option<String>,
     /// The name and arguments of a function that should be called, as generated by the model.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<ChatMessageFunctionCall>,
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 /// The name and arguments of a function that should be called, as generated by the model.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatMessageFunctionCall {
     /// The name of the function to call.
     pub name: String,
@@ -334,6 +543,12 @@ pub struct ChatCompletionChunk {
     pub system_fingerprint: String,
     /// The object type, which is always `chat.completion.chunk`.
     pub object: String,
+    /// Usage statistics for the completion request. Only carried by the last chunk of the stream.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The number of the oldest messages dropped to fit the conversation into the context window of the model. Only carried by the last chunk of the stream, and only if any message is dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated_messages: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -344,15 +559,17 @@ pub struct ChatCompletionChunkChoice {
     pub delta: ChatCompletionChunkChoiceDelta,
     /// Log probability information for the choice.
     pub logprobs: Option<ChatCompletionChunkChoiceLogprobs>,
-    /// The reason the model stopped generating tokens. This will be `stop` if the model hit a natural stop point or a provided stop sequence, `length` if the maximum number of tokens specified in the request was reached, or `function_call` if the model called a function.
+    /// The reason the model stopped generating tokens. This will be `stop` if the model hit a natural stop point or a provided stop sequence, `length` if the maximum number of tokens specified in the request was reached, `tool_calls` if the model called a tool, or `function_call` if the model called a function.
     pub finish_reason: Option<FinishReason>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatCompletionChunkChoiceDelta {
     /// The role of the author of this message.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<ChatCompletionRole>,
     /// The contents of the chunk message.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
     /// The name and arguments of a function that should be called, as generated by the model.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -361,20 +578,70 @@ pub struct ChatCompletionChunkChoiceDelta {
     pub tool_calls: Option<Vec<ToolCall>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A tool call generated by the model.
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ToolCall {
-    index: u32,
-    id: String,
+    /// The index of the tool call in the list of tool calls. Only carried by the chunks of a streamed completion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+    /// The id of the tool call, which is referred by the message of the result.
+    pub id: String,
     /// The type of the tool. Currently, only function is supported.
-    ty: String,
-    function: ToolCallFunction,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub function: ToolCallFunction,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ToolCallFunction {
-    name: String,
-    arguments: String,
+    /// The name of the function to call.
+    pub name: String,
+    /// The arguments to call the function with, as generated by the model in JSON format.
+    pub arguments: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatCompletionChunkChoiceLogprobs;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatten_content_parts() {
+        let message: ChatCompletionRequestMessage = serde_json::from_str(
+            r#"{"role": "user", "content": [
+                {"type": "text", "text": "What is in this image?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+                {"type": "text", "text": "Answer briefly."}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            message.content_text(),
+            "What is in this image?\nAnswer briefly."
+        );
+    }
+
+    #[test]
+    fn read_null_content() {
+        let message: ChatCompletionRequestMessage =
+            serde_json::from_str(r#"{"role": "assistant", "content": null}"#).unwrap();
+
+        assert_eq!(message.content, None);
+        assert_eq!(message.content_text(), "");
+    }
+
+    #[test]
+    fn read_developer_as_system() {
+        let message: ChatCompletionRequestMessage =
+            serde_json::from_str(r#"{"role": "developer", "content": "Be brief."}"#).unwrap();
+
+        assert_eq!(message.role, ChatCompletionRole::System);
+        assert_eq!(message.content_text(), "Be brief.");
+
+        // the alias is lossy, so the message is written back as a system message
+        assert_eq!(serde_json::to_value(&message).unwrap()["role"], "system");
+    }
+}
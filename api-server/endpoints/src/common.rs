@@ -18,13 +18,15 @@ pub struct Usage {
 }
 
 /// The reason the model stopped generating tokens.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum FinishReason {
     /// `stop` if the model hit a natural stop point or a provided stop sequence.
     stop,
-    /// `length` if the maximum number of tokens specified in the request was reached.
+    /// `length` if the maximum number of tokens specified in the request was reached, or the context window of the model is full.
     length,
     /// `function_call` if the model called a function.
     function_call,
+    /// `tool_calls` if the model called a tool.
+    tool_calls,
 }
@@ -117,8 +117,6 @@ pub struct CompletionRequest {
 }
 
 /// Represents a completion response from the API.
-///
-/// Note: both the streamed and non-streamed response objects share the same shape (unlike the chat endpoint).
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CompletionObject {
     /// A unique identifier for the completion.
@@ -141,11 +139,41 @@ pub struct CompletionChoice {
     pub finish_reason: FinishReason,
     /// The index of the choice in the list of choices.
     pub index: u32,
-    /// A chat completion delta generated by streamed model responses.
+    /// Log probability information for the choice.
     pub logprobs: Option<LogprobResult>,
     pub text: String,
 }
 
+/// Represents a streamed chunk of a completion response returned by the model, which carries a token of one of the choices.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompletionChunk {
+    /// A unique identifier for the completion. Each chunk has the same id.
+    pub id: String,
+    /// The choice the token belongs to. It is empty in the last chunk, which carries the usage statistics.
+    pub choices: Vec<CompletionChunkChoice>,
+    /// The Unix timestamp (in seconds) of when the completion was created. Each chunk has the same timestamp.
+    pub created: u64,
+    /// The model used for completion.
+    pub model: String,
+    /// The object type, which is always "text_completion".
+    pub object: String,
+    /// Usage statistics for the completion request. Only carried by the last chunk of the stream.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompletionChunkChoice {
+    /// The reason the model stopped generating tokens, which is only carried by the last chunk of the choice.
+    pub finish_reason: Option<FinishReason>,
+    /// The index of the choice in the list of choices.
+    pub index: u32,
+    /// Log probability information for the choice.
+    pub logprobs: Option<LogprobResult>,
+    /// The text generated since the previous chunk of the choice.
+    pub text: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LogprobResult {
     pub tokens: Vec<String>,
@@ -5,16 +5,30 @@ pub struct EmbeddingsRequestBuilder {
     req: EmbeddingsRequest,
 }
 impl EmbeddingsRequestBuilder {
-    pub fn new(model: impl Into<String>, input: Vec<String>) -> Self {
+    /// Creates a new builder with the given model.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - ID of the model to use.
+    ///
+    /// * `input` - Input text to embed, encoded as a string or array of strings.
+    pub fn new(model: impl Into<String>, input: impl Into<InputText>) -> Self {
         Self {
             req: EmbeddingsRequest {
                 model: model.into(),
-                input,
+                input: input.into(),
+                encoding_format: None,
                 user: None,
             },
         }
     }
 
+    /// Sets the format to return the embeddings in.
+    pub fn with_encoding_format(mut self, encoding_format: EncodingFormat) -> Self {
+        self.req.encoding_format = Some(encoding_format);
+        self
+    }
+
     pub fn with_user(mut self, user: impl Into<String>) -> Self {
         self.req.user = Some(user.into());
         self
@@ -29,19 +43,72 @@ impl EmbeddingsRequestBuilder {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmbeddingsRequest {
     /// ID of the model to use.
-    model: String,
-    /// Input text to embed. Each input must not exceed the max input tokens for the model (8191 tokens for text-embedding-ada-002) and cannot be an empty string.
-    input: Vec<String>,
+    pub model: String,
+    /// Input text to embed, encoded as a string or array of strings. Each input must not exceed the max input tokens for the model and cannot be an empty string.
+    pub input: InputText,
+    /// The format to return the embeddings in. Can be either `float` or `base64`.
+    /// Defaults to `float`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<EncodingFormat>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse.
     #[serde(skip_serializing_if = "Option::is_none")]
-    user: Option<String>,
+    pub user: Option<String>,
+}
+
+/// Defines the types of the input text to embed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InputText {
+    /// A single string.
+    String(String),
+    /// An array of strings.
+    ArrayOfStrings(Vec<String>),
+}
+impl InputText {
+    /// Returns the input texts as a list of strings.
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            InputText::String(text) => vec![text.clone()],
+            InputText::ArrayOfStrings(texts) => texts.clone(),
+        }
+    }
+}
+impl From<&str> for InputText {
+    fn from(s: &str) -> Self {
+        InputText::String(s.to_string())
+    }
+}
+impl From<String> for InputText {
+    fn from(s: String) -> Self {
+        InputText::String(s)
+    }
+}
+impl From<Vec<String>> for InputText {
+    fn from(texts: Vec<String>) -> Self {
+        InputText::ArrayOfStrings(texts)
+    }
+}
+
+/// The format to return the embeddings in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    /// A list of floats.
+    #[default]
+    Float,
+    /// A base64-encoded string of the little-endian `f32` values.
+    Base64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmbeddingsResponse {
-    pub object: Option<String>,
-    pub data: Option<Vec<EmbeddingData>>,
+    /// The object type, which is always "list".
+    pub object: String,
+    /// The list of embeddings generated by the model.
+    pub data: Vec<EmbeddingData>,
+    /// The model used for generating the embeddings.
     pub model: String,
+    /// Usage statistics for the embeddings request.
     pub usage: Usage,
 }
 
@@ -49,9 +116,19 @@ pub struct EmbeddingsResponse {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct EmbeddingData {
     /// The index of the embedding in the list of embeddings.
-    index: u32,
+    pub index: u32,
     /// The object type, which is always "embedding".
-    object: String,
-    /// The embedding vector, which is a list of floats.
-    embedding: Vec<f64>,
+    pub object: String,
+    /// The embedding vector, which is a list of floats or a base64-encoded string depending on the `encoding_format` of the request.
+    pub embedding: EmbeddingVector,
+}
+
+/// The embedding vector in the format requested.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    /// A list of floats.
+    Float(Vec<f64>),
+    /// A base64-encoded string of the little-endian `f32` values.
+    Base64(String),
 }
@@ -1,3 +1,4 @@
+pub mod cancel;
 pub mod chat;
 pub mod common;
 pub mod completions;
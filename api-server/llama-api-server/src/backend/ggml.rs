@@ -1,50 +1,73 @@
-use crate::{error, ModelInfo, MAX_BUFFER_SIZE};
+use crate::{
+    cancel::{self, CancelFlag, Registration},
+    error::{self, ApiError},
+    grammar,
+    scheduler::Scheduler,
+    Graph, Metadata, ModelInfo,
+};
+use base64::Engine;
 use chat_prompts::{
-    chat::{
-        belle::BelleLlama2ChatPrompt,
-        llama::{CodeLlamaInstructPrompt, Llama2ChatPrompt},
-        mistral::{MistralInstructPrompt, MistralLitePrompt},
-        openchat::OpenChatPrompt,
-        BuildChatPrompt, ChatPrompt,
-    },
-    PromptTemplateType,
+    budget::{self, FittedPrompt},
+    chat::{BuildChatPrompt, ChatPrompt},
+    stop::StopMatcher,
+    utf8::Utf8Decoder,
 };
 use endpoints::{
+    cancel::{CancelRequest, CancelResponse},
     chat::{
         ChatCompletionChunk, ChatCompletionChunkChoice, ChatCompletionChunkChoiceDelta,
         ChatCompletionObject, ChatCompletionObjectChoice, ChatCompletionObjectMessage,
-        ChatCompletionRequest, ChatCompletionRole,
+        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionRole,
+        ChatMessageFunctionCall, ResponseFormat, Tool, ToolCall, ToolCallFunction, ToolChoice,
+        ToolChoiceMode,
     },
     common::{FinishReason, Usage},
-    completions::{CompletionChoice, CompletionObject, CompletionRequest},
+    completions::{
+        CompletionChoice, CompletionChunk, CompletionChunkChoice, CompletionObject,
+        CompletionRequest,
+    },
+    embeddings::{
+        EmbeddingData, EmbeddingVector, EmbeddingsRequest, EmbeddingsResponse, EncodingFormat,
+    },
     models::{ListModelsResponse, Model},
 };
-use futures::{future, stream};
-use futures_util::TryStreamExt;
+use futures::stream;
 use hyper::{body::to_bytes, Body, Request, Response};
+use serde::Serialize;
 use serde_json::Value;
-use std::time::SystemTime;
+use std::{convert::Infallible, time::SystemTime};
+use tokio::sync::mpsc;
+
+/// The maximum number of generated tokens waiting to be sent to the client of a streamed completion.
+const TOKEN_CHANNEL_SIZE: usize = 16;
+/// The header of a completion request by which the client chooses the id of the completion, so that a non-streamed completion can be cancelled before its response arrives.
+const COMPLETION_ID_HEADER: &str = "x-completion-id";
 
 /// Lists models available
 pub(crate) async fn models_handler(
-    model_info: ModelInfo,
-    template_ty: PromptTemplateType,
+    models: Vec<ModelInfo>,
     created: u64,
 ) -> Result<Response<Body>, hyper::Error> {
-    let model = Model {
-        id: format!(
-            "{name}:{template}",
-            name = model_info.name,
-            template = template_ty.to_string()
-        ),
-        created: created.clone(),
-        object: String::from("model"),
-        owned_by: String::from("Not specified"),
-    };
+    let data = models
+        .iter()
+        .map(|model_info| Model {
+            id: model_info.id(),
+            created,
+            object: String::from("model"),
+            owned_by: String::from("Not specified"),
+        })
+        .collect();
 
     let list_models_response = ListModelsResponse {
         object: String::from("list"),
-        data: vec![model],
+        data,
+    };
+
+    let body = match serde_json::to_string(&list_models_response) {
+        Ok(body) => body,
+        Err(e) => {
+            return error::internal_server_error(e.to_string());
+        }
     };
 
     // return response
@@ -52,66 +75,434 @@ pub(crate) async fn models_handler(
         .header("Access-Control-Allow-Origin", "*")
         .header("Access-Control-Allow-Methods", "*")
         .header("Access-Control-Allow-Headers", "*")
-        .body(Body::from(
-            serde_json::to_string(&list_models_response).unwrap(),
-        ));
+        .body(Body::from(body));
     match result {
         Ok(response) => Ok(response),
         Err(e) => error::internal_server_error(e.to_string()),
     }
 }
 
-pub(crate) async fn _embeddings_handler() -> Result<Response<Body>, hyper::Error> {
-    println!("llama_embeddings_handler not implemented");
-    error::not_implemented()
+/// Computes the embeddings of the input texts.
+pub(crate) async fn embeddings_handler(
+    mut req: Request<Body>,
+    models: Vec<ModelInfo>,
+) -> Result<Response<Body>, hyper::Error> {
+    println!("[EMBEDDINGS] New embeddings request begins ...");
+
+    // parse request
+    let body_bytes = to_bytes(req.body_mut()).await?;
+    let embeddings_request: EmbeddingsRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(embeddings_request) => embeddings_request,
+        Err(e) => {
+            return error::invalid_json(e.to_string());
+        }
+    };
+
+    // find the requested model
+    let model_info = match find_model(&models, Some(embeddings_request.model.as_str())) {
+        Some(model_info) => model_info,
+        None => {
+            return error::model_not_found(&embeddings_request.model);
+        }
+    };
+
+    let inputs = embeddings_request.input.to_vec();
+    if inputs.is_empty() || inputs.iter().any(|input| input.is_empty()) {
+        return error::bad_request("The input text to embed must not be empty.");
+    }
+    let encoding_format = embeddings_request.encoding_format.unwrap_or_default();
+
+    let scheduler = match scheduler(&model_info.name) {
+        Ok(scheduler) => scheduler,
+        Err(err) => {
+            return error::error_response(err);
+        }
+    };
+
+    let result = scheduler
+        .run(move |graph| {
+            // switch the execution context to the embedding mode
+            let mut metadata = graph.metadata.clone();
+            metadata.embedding = true;
+            graph.update_metadata(&metadata)?;
+
+            let result = compute_embeddings(graph, &inputs, encoding_format);
+
+            // restore the metadata no matter whether the computation succeeds
+            graph.reset_metadata()?;
+
+            result
+        })
+        .await;
+
+    let (data, prompt_tokens) = match result {
+        Ok(Ok(result)) => result,
+        Ok(Err(msg)) => {
+            return error::internal_server_error(msg);
+        }
+        Err(err) => {
+            return error::error_response(err);
+        }
+    };
+
+    println!("[EMBEDDINGS] New embeddings request ends.");
+
+    let embeddings_response = EmbeddingsResponse {
+        object: String::from("list"),
+        data,
+        model: model_info.name.clone(),
+        usage: Usage {
+            prompt_tokens,
+            completion_tokens: 0,
+            total_tokens: prompt_tokens,
+        },
+    };
+
+    let body = match serde_json::to_string(&embeddings_response) {
+        Ok(body) => body,
+        Err(e) => {
+            return error::internal_server_error(e.to_string());
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .body(Body::from(body));
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
+
+/// Computes the embedding of each input text, and returns the embeddings with the total number of input tokens.
+fn compute_embeddings(
+    graph: &mut Graph,
+    inputs: &[String],
+    encoding_format: EncodingFormat,
+) -> Result<(Vec<EmbeddingData>, u32), String> {
+    let mut data = Vec::with_capacity(inputs.len());
+    let mut prompt_tokens = 0;
+    for (index, input) in inputs.iter().enumerate() {
+        // set input
+        if graph
+            .set_input(0, wasi_nn::TensorType::U8, &[1], input.as_bytes())
+            .is_err()
+        {
+            return Err(String::from("Fail to set input tensor"));
+        }
+
+        // compute the embedding
+        if let Err(e) = graph.compute() {
+            return Err(format!("Fail to compute embedding: {msg}", msg = e));
+        }
+
+        // retrieve the embedding, which is serialized as a JSON string by the backend
+        let output_buffer = match graph.get_output(0) {
+            Ok(output_buffer) => output_buffer,
+            Err(e) => {
+                return Err(format!("Fail to get output tensor: {msg}", msg = e));
+            }
+        };
+        let output: Value = match serde_json::from_slice(&output_buffer) {
+            Ok(output) => output,
+            Err(e) => {
+                return Err(format!("Fail to parse embedding: {msg}", msg = e));
+            }
+        };
+        let embedding: Vec<f64> = match output["embedding"].as_array() {
+            Some(values) => values.iter().filter_map(|value| value.as_f64()).collect(),
+            None => {
+                return Err(String::from(
+                    "The output of the model contains no embedding",
+                ));
+            }
+        };
+
+        // get the number of input tokens
+        prompt_tokens += get_token_info(graph)?.input_tokens;
+
+        let embedding = match encoding_format {
+            EncodingFormat::Float => EmbeddingVector::Float(embedding),
+            EncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding
+                    .iter()
+                    .flat_map(|value| (*value as f32).to_le_bytes())
+                    .collect();
+                EmbeddingVector::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+            }
+        };
+
+        data.push(EmbeddingData {
+            index: index as u32,
+            object: String::from("embedding"),
+            embedding,
+        });
+    }
+
+    Ok((data, prompt_tokens))
+}
+
+/// Counts the tokens of the text by setting it as the input tensor. A text longer than the context window is counted as `usize::MAX` tokens, so that it exceeds any budget.
+fn count_tokens(graph: &mut Graph, text: &str) -> Result<usize, String> {
+    match set_prompt(graph, text) {
+        Ok(()) => Ok(get_token_info(graph)?.input_tokens as usize),
+        Err(ApiError::ContextLengthExceeded) => Ok(usize::MAX),
+        Err(_) => Err(String::from("Fail to set input tensor")),
+    }
+}
+
+/// Sets the prompt as the input tensor. The backend rejects a prompt longer than the context window of the model, which is a client error.
+fn set_prompt(graph: &mut Graph, prompt: &str) -> Result<(), ApiError> {
+    match graph.set_input(0, wasi_nn::TensorType::U8, &[1], prompt.as_bytes()) {
+        Ok(()) => Ok(()),
+        Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::PromptTooLong)) => {
+            Err(ApiError::ContextLengthExceeded)
+        }
+        Err(_) => Err(ApiError::Internal(String::from("Fail to set input tensor"))),
+    }
+}
+
+/// Finds the model requested by name. The name may be either the model name or the model id listed by the `/v1/models` endpoint. If no model is specified, the first loaded model is used.
+fn find_model<'a>(models: &'a [ModelInfo], name: Option<&str>) -> Option<&'a ModelInfo> {
+    match name {
+        Some(name) if !name.is_empty() => models
+            .iter()
+            .find(|model_info| model_info.name == name || model_info.id() == name),
+        _ => models.first(),
+    }
+}
+
+/// Returns the scheduler of the model with the given name.
+fn scheduler(name: impl AsRef<str>) -> Result<&'static Scheduler, ApiError> {
+    let schedulers = match crate::SCHEDULERS.get() {
+        Some(schedulers) => schedulers,
+        None => {
+            return Err(ApiError::Internal(String::from(
+                "The SCHEDULERS is not initialized",
+            )))
+        }
+    };
+    match schedulers.get(name.as_ref()) {
+        Some(scheduler) => Ok(scheduler),
+        None => Err(ApiError::Internal(format!(
+            "The scheduler of the model is not found: {name}",
+            name = name.as_ref()
+        ))),
+    }
+}
+
+/// Returns the id of a completion, which is given by the client in the `X-Completion-Id` header of the request, or generated with the given prefix.
+fn completion_id(req: &Request<Body>, prefix: &str) -> String {
+    match req
+        .headers()
+        .get(COMPLETION_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+    {
+        Some(id) if !id.is_empty() => String::from(id),
+        _ => format!("{prefix}-{}", uuid::Uuid::new_v4()),
+    }
+}
+
+/// Returns the error of a completion, which is `Cancelled` if the completion fails because it is cancelled.
+fn cancelled_or(registration: &Registration, err: ApiError) -> ApiError {
+    match registration.flag().is_cancelled() {
+        true => ApiError::Cancelled,
+        false => err,
+    }
+}
+
+/// Cancels a running completion or chat completion by its id. The generation stops before its next token, and the request of the completion is answered with a `cancelled` error.
+pub(crate) async fn cancel_handler(mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    // parse request
+    let body_bytes = to_bytes(req.body_mut()).await?;
+    let cancel_request: CancelRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(cancel_request) => cancel_request,
+        Err(e) => {
+            return error::invalid_json(e.to_string());
+        }
+    };
+
+    if !cancel::cancel(&cancel_request.id) {
+        return error::error_response(ApiError::CompletionNotFound(cancel_request.id));
+    }
+    println!("[CANCEL] Completion {} is cancelled.", cancel_request.id);
+
+    let cancel_response = CancelResponse {
+        id: cancel_request.id,
+        object: String::from("completion.cancelled"),
+    };
+
+    let body = match serde_json::to_string(&cancel_response) {
+        Ok(body) => body,
+        Err(e) => {
+            return error::internal_server_error(e.to_string());
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .body(Body::from(body));
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
 }
 
 pub(crate) async fn completions_handler(
     mut req: Request<Body>,
+    models: Vec<ModelInfo>,
 ) -> Result<Response<Body>, hyper::Error> {
     println!("[COMPLETION] New completion begins ...");
 
     // parse request
     let body_bytes = to_bytes(req.body_mut()).await?;
-    let completion_request: CompletionRequest = serde_json::from_slice(&body_bytes).unwrap();
+    let completion_request: CompletionRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(completion_request) => completion_request,
+        Err(e) => {
+            return error::invalid_json(e.to_string());
+        }
+    };
 
-    let prompt = completion_request.prompt.join(" ");
+    // find the requested model
+    let model_info = match find_model(&models, completion_request.model.as_deref()) {
+        Some(model_info) => model_info,
+        None => {
+            return error::model_not_found(completion_request.model.unwrap_or_default());
+        }
+    };
 
-    // ! todo: a temp solution of computing the number of tokens in prompt
-    let prompt_tokens = prompt.split_whitespace().count() as u32;
+    let echo = completion_request.echo.unwrap_or(false);
 
-    let buffer = match infer(prompt.trim()).await {
-        Ok(buffer) => buffer,
-        Err(e) => {
-            return error::internal_server_error(e.to_string());
+    // fill in the middle if the code after the hole is given
+    let fim = match completion_request.suffix.as_deref() {
+        Some(_) if echo => {
+            return error::error_response(ApiError::InvalidParameter {
+                param: String::from("echo"),
+                message: String::from("`echo` is not supported with `suffix`"),
+            });
         }
+        Some(suffix) => match model_info.template.fim_format() {
+            Some(format) => Some((format, suffix)),
+            None => {
+                return error::error_response(ApiError::InvalidParameter {
+                    param: String::from("suffix"),
+                    message: format!(
+                        "The prompt template `{ty}` of the model does not support fill-in-the-middle",
+                        ty = model_info.template_ty
+                    ),
+                });
+            }
+        },
+        None => None,
     };
 
-    // convert inference result to string
-    let model_answer = String::from_utf8(buffer.clone()).unwrap();
-    let answer = model_answer.trim();
+    // the code around the hole is kept as is, since its whitespaces matter
+    let text = completion_request.prompt.join(" ");
+    let prompt = match &fim {
+        Some((format, suffix)) => format.build(&text, suffix),
+        None => String::from(text.trim()),
+    };
+
+    // sampling parameters of the request
+    let sampling = SamplingOptions::from(&completion_request);
+    if let Err(err) = sampling.validate() {
+        return error::error_response(err);
+    }
+    let mut stops = sampling.stops(&[]);
+    if let Some((format, _)) = &fim {
+        stops.extend(format.stop.iter().cloned());
+    }
+
+    // the completion can be cancelled by its id until it ends
+    let registration = match Registration::new(completion_id(&req, "cmpl")) {
+        Ok(registration) => registration,
+        Err(err) => {
+            return error::error_response(err);
+        }
+    };
+
+    if completion_request.stream.unwrap_or(false) {
+        println!("[COMPLETION] Streaming the completion ...");
+        return stream_completion(
+            &model_info.name,
+            prompt,
+            echo,
+            sampling,
+            stops,
+            registration,
+        );
+    }
+
+    let outputs = match infer(&model_info.name, &prompt, sampling, registration.flag()).await {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            return error::error_response(cancelled_or(&registration, err));
+        }
+    };
 
-    // ! todo: a temp solution of computing the number of tokens in answer
-    let completion_tokens = answer.split_whitespace().count() as u32;
+    let prompt_tokens = outputs
+        .first()
+        .map(|output| output.token_info.input_tokens)
+        .unwrap_or_default();
+    let completion_tokens = outputs
+        .iter()
+        .map(|output| output.token_info.output_tokens)
+        .sum();
 
-    println!("[COMPLETION] Bot answer: {}", answer);
+    // convert inference results to strings
+    let mut answers = Vec::with_capacity(outputs.len());
+    for Inference {
+        output,
+        finish_reason,
+        ..
+    } in outputs
+    {
+        let model_answer = String::from_utf8_lossy(&output);
+        let kept = truncate_at_stop(&model_answer, &stops);
+        // the answer ends at the stop sequence even if the generation goes on after it
+        let finish_reason = match kept.len() < model_answer.len() {
+            true => FinishReason::stop,
+            false => finish_reason,
+        };
+        let answer = match fim {
+            Some(_) => kept,
+            None => kept.trim(),
+        };
+        println!("[COMPLETION] Bot answer: {}", answer);
+
+        let text = match echo {
+            true => format!("{prompt}{answer}"),
+            false => String::from(answer),
+        };
+        answers.push((text, finish_reason));
+    }
 
     println!("[COMPLETION] New completion ends.");
 
     let completion_object = CompletionObject {
-        id: uuid::Uuid::new_v4().to_string(),
+        id: String::from(registration.id()),
         object: String::from("text_completion"),
         created: SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs(),
-        model: completion_request.model.clone().unwrap_or_default(),
-        choices: vec![CompletionChoice {
-            index: 0,
-            text: String::from(answer),
-            finish_reason: FinishReason::stop,
-            logprobs: None,
-        }],
+        model: model_info.name.clone(),
+        choices: answers
+            .into_iter()
+            .enumerate()
+            .map(|(index, (text, finish_reason))| CompletionChoice {
+                index: index as u32,
+                text,
+                finish_reason,
+                logprobs: None,
+            })
+            .collect(),
         usage: Usage {
             prompt_tokens,
             completion_tokens,
@@ -119,14 +510,169 @@ pub(crate) async fn completions_handler(
         },
     };
 
+    let body = match serde_json::to_string(&completion_object) {
+        Ok(body) => body,
+        Err(e) => {
+            return error::internal_server_error(e.to_string());
+        }
+    };
+
     // return response
     let result = Response::builder()
         .header("Access-Control-Allow-Origin", "*")
         .header("Access-Control-Allow-Methods", "*")
         .header("Access-Control-Allow-Headers", "*")
-        .body(Body::from(
-            serde_json::to_string(&completion_object).unwrap(),
-        ));
+        .body(Body::from(body));
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
+
+/// Streams the choices of a completion as server-sent events. Each chunk carries a token of a choice, the last chunk of each choice carries its finish reason, and the stream ends with a chunk carrying the usage statistics of all choices. If `echo` is set, the first chunk of each choice carries the prompt.
+fn stream_completion(
+    model_name: &str,
+    prompt: String,
+    echo: bool,
+    sampling: SamplingOptions,
+    stops: Vec<String>,
+    registration: Registration,
+) -> Result<Response<Body>, hyper::Error> {
+    let scheduler = match scheduler(model_name) {
+        Ok(scheduler) => scheduler,
+        Err(err) => {
+            return error::error_response(err);
+        }
+    };
+
+    // all chunks of the completion share the same id and creation time
+    let id = String::from(registration.id());
+
+    // the tokens are generated by a job of the scheduler, and sent to the stream through the channel
+    let (sender, receiver) = mpsc::channel(TOKEN_CHANNEL_SIZE);
+    let sender = TokenSender {
+        sender,
+        registration,
+    };
+    if let Err(err) = scheduler.submit(Box::new(move |mut graph| {
+        Box::pin(async move {
+            stream_choices(&mut graph, &sampling, &prompt, echo, &stops, None, sender).await;
+            graph
+        })
+    })) {
+        return error::error_response(err);
+    }
+
+    let created = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let model = String::from(model_name);
+    let stream = stream::unfold(
+        (receiver, StreamState::Generating),
+        move |(mut receiver, state)| {
+            let id = id.clone();
+            let model = model.clone();
+            async move {
+                match state {
+                    StreamState::Generating | StreamState::Started => match receiver.recv().await {
+                        Some(Ok(Generated::Token { index, token })) => {
+                            let chunk = CompletionChunk {
+                                id,
+                                object: String::from("text_completion"),
+                                created,
+                                model,
+                                choices: vec![CompletionChunkChoice {
+                                    index,
+                                    text: token,
+                                    logprobs: None,
+                                    finish_reason: None,
+                                }],
+                                usage: None,
+                            };
+                            Some((Ok(sse_event(&chunk)), (receiver, StreamState::Started)))
+                        }
+                        Some(Ok(Generated::Stopped {
+                            index,
+                            finish_reason,
+                        })) => {
+                            let chunk = CompletionChunk {
+                                id,
+                                object: String::from("text_completion"),
+                                created,
+                                model,
+                                choices: vec![CompletionChunkChoice {
+                                    index,
+                                    text: String::new(),
+                                    logprobs: None,
+                                    finish_reason: Some(finish_reason),
+                                }],
+                                usage: None,
+                            };
+                            Some((Ok(sse_event(&chunk)), (receiver, StreamState::Generating)))
+                        }
+                        Some(Ok(Generated::Finished {
+                            index,
+                            finish_reason,
+                            usage,
+                            ..
+                        })) => {
+                            let finish_chunk = CompletionChunk {
+                                id: id.clone(),
+                                object: String::from("text_completion"),
+                                created,
+                                model: model.clone(),
+                                choices: vec![CompletionChunkChoice {
+                                    index,
+                                    text: String::new(),
+                                    logprobs: None,
+                                    finish_reason: Some(finish_reason),
+                                }],
+                                usage: None,
+                            };
+                            let usage_chunk = CompletionChunk {
+                                id,
+                                object: String::from("text_completion"),
+                                created,
+                                model,
+                                choices: vec![],
+                                usage: Some(usage),
+                            };
+                            Some((
+                                Ok(format!(
+                                    "{finish}{usage}",
+                                    finish = sse_event(&finish_chunk),
+                                    usage = sse_event(&usage_chunk)
+                                )),
+                                (receiver, StreamState::Finished),
+                            ))
+                        }
+                        Some(Err(err)) => {
+                            Some((Ok(sse_api_error(err)), (receiver, StreamState::Ended)))
+                        }
+                        None => Some((
+                            Ok(sse_error("The generation is interrupted")),
+                            (receiver, StreamState::Ended),
+                        )),
+                    },
+                    StreamState::Finished => Some((
+                        Ok::<_, Infallible>(String::from("data: [DONE]\n\n")),
+                        (receiver, StreamState::Ended),
+                    )),
+                    StreamState::Ended => None,
+                }
+            }
+        },
+    );
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Connection", "keep-alive")
+        .body(Body::wrap_stream(stream));
     match result {
         Ok(response) => Ok(response),
         Err(e) => error::internal_server_error(e.to_string()),
@@ -136,10 +682,9 @@ pub(crate) async fn completions_handler(
 /// Processes a chat-completion request and returns a chat-completion response with the answer from the model.
 pub(crate) async fn chat_completions_handler(
     mut req: Request<Body>,
-    template_ty: PromptTemplateType,
+    models: Vec<ModelInfo>,
     log_prompts: bool,
-    stream: bool,
-    stop: Option<String>,
+    reverse_prompts: Vec<String>,
 ) -> Result<Response<Body>, hyper::Error> {
     if req.method().eq(&hyper::http::Method::OPTIONS) {
         let result = Response::builder()
@@ -156,358 +701,1466 @@ pub(crate) async fn chat_completions_handler(
         }
     }
 
-    fn create_prompt_template(template_ty: PromptTemplateType) -> ChatPrompt {
-        match template_ty {
-            PromptTemplateType::Llama2Chat => {
-                ChatPrompt::Llama2ChatPrompt(Llama2ChatPrompt::default())
-            }
-            PromptTemplateType::MistralInstruct => {
-                ChatPrompt::MistralInstructPrompt(MistralInstructPrompt::default())
-            }
-            PromptTemplateType::MistralLite => {
-                ChatPrompt::MistralLitePrompt(MistralLitePrompt::default())
-            }
-            PromptTemplateType::OpenChat => ChatPrompt::OpenChatPrompt(OpenChatPrompt::default()),
-            PromptTemplateType::CodeLlama => {
-                ChatPrompt::CodeLlamaInstructPrompt(CodeLlamaInstructPrompt::default())
-            }
-            PromptTemplateType::BelleLlama2Chat => {
-                ChatPrompt::BelleLlama2ChatPrompt(BelleLlama2ChatPrompt::default())
-            }
-            PromptTemplateType::VicunaChat => {
-                ChatPrompt::VicunaChatPrompt(chat_prompts::chat::vicuna::VicunaChatPrompt::default())
-            }
-            PromptTemplateType::Vicuna11Chat => ChatPrompt::Vicuna11ChatPrompt(
-                chat_prompts::chat::vicuna::Vicuna11ChatPrompt::default(),
-            ),
-            PromptTemplateType::ChatML => {
-                ChatPrompt::ChatMLPrompt(chat_prompts::chat::chatml::ChatMLPrompt::default())
-            }
-            PromptTemplateType::Baichuan2 => ChatPrompt::Baichuan2ChatPrompt(
-                chat_prompts::chat::baichuan::Baichuan2ChatPrompt::default(),
-            ),
-            PromptTemplateType::WizardCoder => ChatPrompt::WizardCoderPrompt(
-                chat_prompts::chat::wizard::WizardCoderPrompt::default(),
-            ),
-            PromptTemplateType::Zephyr => {
-                ChatPrompt::ZephyrChatPrompt(chat_prompts::chat::zephyr::ZephyrChatPrompt::default())
-            }
-            PromptTemplateType::IntelNeural => {
-                ChatPrompt::NeuralChatPrompt(chat_prompts::chat::intel::NeuralChatPrompt::default())
-            }
-            PromptTemplateType::DeepseekChat => ChatPrompt::DeepseekChatPrompt(
-                chat_prompts::chat::deepseek::DeepseekChatPrompt::default(),
-            ),
-            PromptTemplateType::DeepseekCoder => ChatPrompt::DeepseekCoderPrompt(
-                chat_prompts::chat::deepseek::DeepseekCoderPrompt::default(),
-            ),
+    // parse request
+    let body_bytes = to_bytes(req.body_mut()).await?;
+    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(chat_request) => chat_request,
+        Err(e) => {
+            return error::invalid_json(e.to_string());
+        }
+    };
+
+    // find the requested model
+    let model_info = match find_model(&models, chat_request.model.as_deref()) {
+        Some(model_info) => model_info.clone(),
+        None => {
+            return error::model_not_found(chat_request.model.unwrap_or_default());
         }
+    };
+    let template = model_info.template.clone();
+
+    // sampling parameters of the request
+    let sampling = SamplingOptions::from(&chat_request);
+    if let Err(err) = sampling.validate() {
+        return error::error_response(err);
     }
-    let template = create_prompt_template(template_ty);
+    let mut stops = sampling.stops(&reverse_prompts);
+    stops.extend(template.stop_sequences());
 
-    // parse request
-    let body_bytes = to_bytes(req.body_mut()).await?;
-    let mut chat_request: ChatCompletionRequest = serde_json::from_slice(&body_bytes).unwrap();
+    // the tools the model may call
+    let (tools, legacy_functions) = requested_tools(&chat_request);
 
-    // build prompt
-    let prompt = match template.build(chat_request.messages.as_mut()) {
+    // build prompt, which is rebuilt by the job with the oldest turns dropped if it does not fit into the context window
+    let prompt = match template.build_with_tools(&mut chat_request.messages.clone(), &tools) {
         Ok(prompt) => prompt,
         Err(e) => {
-            return error::internal_server_error(e.to_string());
+            return error::prompt_error(e);
         }
     };
+    let mut messages = std::mem::take(&mut chat_request.messages);
 
     if log_prompts {
         println!("\n---------------- [LOG: PROMPT] ---------------------\n");
         println!("{}", &prompt);
         println!("\n----------------------------------------------------\n");
     }
-    let mut graph = crate::GRAPH.get().unwrap().lock().unwrap();
+    let scheduler = match scheduler(&model_info.name) {
+        Ok(scheduler) => scheduler,
+        Err(err) => {
+            return error::error_response(err);
+        }
+    };
 
-    // set input
-    let tensor_data = prompt.as_bytes().to_vec();
-    if graph
-        .set_input(0, wasi_nn::TensorType::U8, &[1], &tensor_data)
-        .is_err()
-    {
-        return error::internal_server_error(String::from("Fail to set input tensor"));
+    // the completion can be cancelled by its id until it ends
+    let registration = match Registration::new(completion_id(&req, "chatcmpl")) {
+        Ok(registration) => registration,
+        Err(err) => {
+            return error::error_response(err);
+        }
     };
 
-    let result = match stream {
+    // the tool calls and the format of the answer can only be checked in the whole answer, so such a completion is streamed after it is generated
+    let stream = chat_request.stream.unwrap_or(false);
+    let formatted = !matches!(
+        chat_request.response_format,
+        None | Some(ResponseFormat::Text)
+    );
+    let result = match stream && tools.is_empty() && !formatted {
         true => {
-            let model = chat_request.model.clone().unwrap_or_default();
-            let stream = stream::repeat_with(move || {
-                let mut graph = crate::GRAPH.get().unwrap().lock().unwrap();
-                // compute
-                match graph.compute_single() {
-                    Ok(_) => {
-                        // Retrieve the output.
-                        let mut output_buffer = vec![0u8; *MAX_BUFFER_SIZE.get().unwrap()];
-                        let mut output_size = match graph.get_output_single(0, &mut output_buffer) {
-                            Ok(size) => size,
-                            Err(e) => {
-                                return Err(format!(
-                                    "Fail to get output tensor: {msg}",
-                                    msg = e.to_string()
-                                ));
-                            }
-                        };
-                        output_size = std::cmp::min(*MAX_BUFFER_SIZE.get().unwrap(), output_size);
-
-                        let output =
-                            String::from_utf8_lossy(&output_buffer[..output_size]).to_string();
-
-                        if let Some(stop) = &stop {
-                            if output.contains(stop) {
-                                return Ok("[GGML] End of sequence".to_string());
-                            }
+            // all chunks of the completion share the same id and creation time
+            let id = String::from(registration.id());
+
+            // the tokens are generated by a job of the scheduler, and sent to the stream through the channel
+            let (sender, receiver) = mpsc::channel(TOKEN_CHANNEL_SIZE);
+            let sender = TokenSender {
+                sender,
+                registration,
+            };
+            if let Err(err) = scheduler.submit(Box::new(move |mut graph| {
+                Box::pin(async move {
+                    stream_tokens(
+                        &mut graph,
+                        &sampling,
+                        &template,
+                        &mut messages,
+                        &tools,
+                        &stops,
+                        sender,
+                    )
+                    .await;
+                    graph
+                })
+            })) {
+                return error::error_response(err);
+            }
+
+            let created = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let model = model_info.name.clone();
+            let stream = stream::unfold(
+                (receiver, StreamState::Generating),
+                move |(mut receiver, state)| {
+                    let id = id.clone();
+                    let model = model.clone();
+                    async move {
+                        match state {
+                            StreamState::Generating | StreamState::Started => match receiver
+                                .recv()
+                                .await
+                            {
+                                Some(Ok(Generated::Token { index, token })) => {
+                                    // the role is only carried by the first chunk of each choice
+                                    let role = match state {
+                                        StreamState::Generating => {
+                                            Some(ChatCompletionRole::Assistant)
+                                        }
+                                        _ => None,
+                                    };
+                                    let chunk = ChatCompletionChunk {
+                                        id,
+                                        object: "chat.completion.chunk".to_string(),
+                                        created,
+                                        model,
+                                        system_fingerprint: crate::SYSTEM_FINGERPRINT
+                                            .get()
+                                            .cloned()
+                                            .unwrap_or_default(),
+                                        choices: vec![ChatCompletionChunkChoice {
+                                            index,
+                                            delta: ChatCompletionChunkChoiceDelta {
+                                                role,
+                                                content: Some(token),
+                                                function_call: None,
+                                                tool_calls: None,
+                                            },
+                                            logprobs: None,
+                                            finish_reason: None,
+                                        }],
+                                        usage: None,
+                                        truncated_messages: None,
+                                    };
+                                    Some((Ok(sse_event(&chunk)), (receiver, StreamState::Started)))
+                                }
+                                Some(Ok(Generated::Stopped {
+                                    index,
+                                    finish_reason,
+                                })) => {
+                                    let chunk = ChatCompletionChunk {
+                                        id,
+                                        object: "chat.completion.chunk".to_string(),
+                                        created,
+                                        model,
+                                        system_fingerprint: crate::SYSTEM_FINGERPRINT
+                                            .get()
+                                            .cloned()
+                                            .unwrap_or_default(),
+                                        choices: vec![ChatCompletionChunkChoice {
+                                            index,
+                                            delta: ChatCompletionChunkChoiceDelta {
+                                                role: None,
+                                                content: None,
+                                                function_call: None,
+                                                tool_calls: None,
+                                            },
+                                            logprobs: None,
+                                            finish_reason: Some(finish_reason),
+                                        }],
+                                        usage: None,
+                                        truncated_messages: None,
+                                    };
+                                    // the next choice starts with the role again
+                                    Some((
+                                        Ok(sse_event(&chunk)),
+                                        (receiver, StreamState::Generating),
+                                    ))
+                                }
+                                Some(Ok(Generated::Finished {
+                                    index,
+                                    finish_reason,
+                                    usage,
+                                    truncated_messages,
+                                })) => {
+                                    let chunk = ChatCompletionChunk {
+                                        id,
+                                        object: "chat.completion.chunk".to_string(),
+                                        created,
+                                        model,
+                                        system_fingerprint: crate::SYSTEM_FINGERPRINT
+                                            .get()
+                                            .cloned()
+                                            .unwrap_or_default(),
+                                        choices: vec![ChatCompletionChunkChoice {
+                                            index,
+                                            delta: ChatCompletionChunkChoiceDelta {
+                                                role: None,
+                                                content: None,
+                                                function_call: None,
+                                                tool_calls: None,
+                                            },
+                                            logprobs: None,
+                                            finish_reason: Some(finish_reason),
+                                        }],
+                                        usage: Some(usage),
+                                        truncated_messages,
+                                    };
+                                    Some((Ok(sse_event(&chunk)), (receiver, StreamState::Finished)))
+                                }
+                                Some(Err(err)) => {
+                                    Some((Ok(sse_api_error(err)), (receiver, StreamState::Ended)))
+                                }
+                                None => Some((
+                                    Ok(sse_error("The generation is interrupted")),
+                                    (receiver, StreamState::Ended),
+                                )),
+                            },
+                            StreamState::Finished => Some((
+                                Ok::<_, Infallible>(String::from("data: [DONE]\n\n")),
+                                (receiver, StreamState::Ended),
+                            )),
+                            StreamState::Ended => None,
                         }
+                    }
+                },
+            );
 
-                        let chat_completion_chunk = ChatCompletionChunk {
-                            id: "chatcmpl-123".to_string(),
+            Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .header("Connection", "keep-alive")
+                .body(Body::wrap_stream(stream))
+        }
+        false => {
+            let job_tools = tools.clone();
+            let choices = sampling.choices();
+            let cancel = registration.flag();
+            let result = scheduler
+                .run_async(move |graph| {
+                    Box::pin(async move {
+                        // drop the oldest turns if the conversation does not fit into the context window
+                        let fitted = fit_context(
+                            graph,
+                            &template,
+                            &mut messages,
+                            &job_tools,
+                            sampling.max_tokens,
+                        )?;
+
+                        // apply the sampling parameters of the request
+                        let metadata = sampling.to_metadata(&graph.metadata)?;
+                        let result = run_chat_choices(
+                            graph,
+                            &fitted.prompt,
+                            &template,
+                            &stops,
+                            &metadata,
+                            choices,
+                            &cancel,
+                        )
+                        .await;
+
+                        // restore the metadata no matter whether the inference succeeds
+                        graph.reset_metadata().map_err(ApiError::Internal)?;
+
+                        let (answers, prompt_tokens, completion_tokens) = result?;
+                        let truncated_messages =
+                            (fitted.dropped_messages > 0).then_some(fitted.dropped_messages as u32);
+                        Ok((
+                            answers,
+                            prompt_tokens,
+                            completion_tokens,
+                            truncated_messages,
+                        ))
+                    })
+                })
+                .await;
+
+            let (answers, prompt_tokens, completion_tokens, truncated_messages) = match result {
+                Ok(Ok(result)) => result,
+                Ok(Err(err)) | Err(err) => {
+                    return error::error_response(cancelled_or(&registration, err));
+                }
+            };
+
+            let mut replies = Vec::with_capacity(answers.len());
+            for ChatAnswer {
+                text: answer,
+                finish_reason,
+            } in answers
+            {
+                // extract the tool calls from the answer
+                let (content, tool_calls) = match tools.is_empty() {
+                    true => (answer, vec![]),
+                    false => model_info.template.parse_tool_calls(&answer),
+                };
+
+                // check the format of the answer
+                if let Some(response_format) = &chat_request.response_format {
+                    if tool_calls.is_empty() {
+                        if let Err(msg) = grammar::validate(&content, response_format) {
+                            return error::error_response(ApiError::InvalidOutput(msg));
+                        }
+                    }
+                }
+                let (message, finish_reason) =
+                    assistant_message(content, tool_calls, legacy_functions, finish_reason);
+                replies.push((message, finish_reason));
+            }
+            let usage = Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            };
+
+            let id = String::from(registration.id());
+            let created = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            match stream {
+                true => {
+                    // each answer is sent in one chunk, followed by the chunk of its finish reason, and the usage is carried by the last chunk
+                    let system_fingerprint =
+                        crate::SYSTEM_FINGERPRINT.get().cloned().unwrap_or_default();
+                    let last = replies.len() - 1;
+                    let mut usage = Some(usage);
+                    let mut chunks = Vec::with_capacity(replies.len() * 2);
+                    for (index, (message, finish_reason)) in replies.into_iter().enumerate() {
+                        let tool_calls = message.tool_calls.map(|tool_calls| {
+                            tool_calls
+                                .into_iter()
+                                .enumerate()
+                                .map(|(index, tool_call)| ToolCall {
+                                    index: Some(index as u32),
+                                    ..tool_call
+                                })
+                                .collect()
+                        });
+                        chunks.push(ChatCompletionChunk {
+                            id: id.clone(),
                             object: "chat.completion.chunk".to_string(),
-                            created: SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
-                            model: model.clone(),
-                            system_fingerprint: "fp_44709d6fcb".to_string(),
+                            created,
+                            model: model_info.name.clone(),
+                            system_fingerprint: system_fingerprint.clone(),
                             choices: vec![ChatCompletionChunkChoice {
-                                index: 0,
+                                index: index as u32,
                                 delta: ChatCompletionChunkChoiceDelta {
                                     role: Some(ChatCompletionRole::Assistant),
-                                    content: Some(output),
+                                    content: message.content.filter(|content| !content.is_empty()),
+                                    function_call: message.function_call,
+                                    tool_calls,
+                                },
+                                logprobs: None,
+                                finish_reason: None,
+                            }],
+                            usage: None,
+                            truncated_messages: None,
+                        });
+                        chunks.push(ChatCompletionChunk {
+                            id: id.clone(),
+                            object: "chat.completion.chunk".to_string(),
+                            created,
+                            model: model_info.name.clone(),
+                            system_fingerprint: system_fingerprint.clone(),
+                            choices: vec![ChatCompletionChunkChoice {
+                                index: index as u32,
+                                delta: ChatCompletionChunkChoiceDelta {
+                                    role: None,
+                                    content: None,
                                     function_call: None,
                                     tool_calls: None,
                                 },
                                 logprobs: None,
-                                finish_reason: None,
+                                finish_reason: Some(finish_reason),
                             }],
-                        };
-
-                        Ok(serde_json::to_string(&chat_completion_chunk).unwrap())
-                    }
-                    Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::EndOfSequence)) => {
-                        Ok("[GGML] End of sequence".to_string())
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", &e);
-                        return Err(e.to_string());
+                            usage: match index == last {
+                                true => usage.take(),
+                                false => None,
+                            },
+                            truncated_messages: truncated_messages.filter(|_| index == last),
+                        });
                     }
+                    let mut events: Vec<String> = chunks.iter().map(sse_event).collect();
+                    events.push(String::from("data: [DONE]\n\n"));
+                    let stream = stream::iter(events.into_iter().map(Ok::<_, Infallible>));
+
+                    Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .header("Content-Type", "text/event-stream")
+                        .header("Cache-Control", "no-cache")
+                        .header("Connection", "keep-alive")
+                        .body(Body::wrap_stream(stream))
                 }
-            });
+                false => {
+                    // create ChatCompletionResponse
+                    let chat_completion_obejct = ChatCompletionObject {
+                        id,
+                        object: String::from("chat.completion"),
+                        created,
+                        model: model_info.name.clone(),
+                        choices: replies
+                            .into_iter()
+                            .enumerate()
+                            .map(
+                                |(index, (message, finish_reason))| ChatCompletionObjectChoice {
+                                    index: index as u32,
+                                    message,
+                                    finish_reason,
+                                },
+                            )
+                            .collect(),
+                        usage,
+                        truncated_messages,
+                    };
 
-            let stream =
-                stream.try_take_while(|x| future::ready(Ok(x != "[GGML] End of sequence")));
+                    let body = match serde_json::to_string(&chat_completion_obejct) {
+                        Ok(body) => body,
+                        Err(e) => {
+                            return error::internal_server_error(e.to_string());
+                        }
+                    };
 
-            Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .body(Body::wrap_stream(stream))
-        }
-        false => {
-            // get the number of input tokens
-            let mut token_info_buffer = vec![0u8; *MAX_BUFFER_SIZE.get().unwrap()];
-            let mut size_token_info = graph.get_output(1, &mut token_info_buffer).unwrap();
-            size_token_info = std::cmp::min(*MAX_BUFFER_SIZE.get().unwrap(), size_token_info);
-            let token_info: Value =
-                serde_json::from_slice(&token_info_buffer[..size_token_info]).unwrap();
-            let prompt_tokens = token_info["input_tokens"].as_i64().unwrap() as u32;
-
-            // execute the inference
-            if graph.compute().is_err() {
-                return error::internal_server_error(String::from(
-                    "Fail to execute model inference",
-                ));
+                    // return response
+                    Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::from(body))
+                }
             }
+        }
+    };
 
-            // Retrieve the output.
-            let mut output_buffer = vec![0u8; *MAX_BUFFER_SIZE.get().unwrap()];
-            let mut output_size = match graph.get_output(0, &mut output_buffer) {
-                Ok(size) => size,
-                Err(e) => {
-                    return error::internal_server_error(format!(
-                        "Fail to get output tensor: {msg}",
-                        msg = e.to_string()
-                    ));
-                }
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
+
+/// Returns the tools the model may call in answering the request, and whether they are given as the deprecated `functions`. No tool is returned if the request does not let the model call any.
+///
+/// The model cannot be forced to call tools, so `required` works like `auto`, and a named tool is the only tool the model is told about.
+fn requested_tools(request: &ChatCompletionRequest) -> (Vec<Tool>, bool) {
+    match (&request.tools, &request.functions) {
+        (Some(tools), _) => {
+            let tools = match &request.tool_choice {
+                Some(ToolChoice::Mode(ToolChoiceMode::None)) => vec![],
+                Some(ToolChoice::Tool(choice)) => tools
+                    .iter()
+                    .filter(|tool| tool.function.name == choice.function.name)
+                    .cloned()
+                    .collect(),
+                _ => tools.clone(),
             };
-            output_size = std::cmp::min(*MAX_BUFFER_SIZE.get().unwrap(), output_size);
+            (tools, false)
+        }
+        (None, Some(functions)) => match request.function_call.as_deref() {
+            Some("none") => (vec![], true),
+            _ => (functions.iter().cloned().map(Tool::new).collect(), true),
+        },
+        (None, None) => (vec![], false),
+    }
+}
+
+/// Creates the assistant message of the answer with its tool calls, and the finish reason of the answer, which is the one of the generation unless the model calls tools. The first call is returned as `function_call` if the tools are given as the deprecated `functions`. The content is `null` if the model only calls tools.
+fn assistant_message(
+    content: String,
+    tool_calls: Vec<ToolCallFunction>,
+    legacy_functions: bool,
+    finish_reason: FinishReason,
+) -> (ChatCompletionObjectMessage, FinishReason) {
+    let mut message = ChatCompletionObjectMessage {
+        role: ChatCompletionRole::Assistant,
+        content: (tool_calls.is_empty() || !content.is_empty()).then_some(content),
+        function_call: None,
+        tool_calls: None,
+    };
 
-            // convert inference result to string
-            let output = std::str::from_utf8(&output_buffer[..output_size]).unwrap();
-            // post-process
-            let message = post_process(&output, template_ty);
+    if tool_calls.is_empty() {
+        return (message, finish_reason);
+    }
 
-            // set input for computing the number of output tokens
-            let tensor_data = message.as_bytes().to_vec();
-            if graph
-                .set_input(0, wasi_nn::TensorType::U8, &[1], &tensor_data)
+    match legacy_functions {
+        true => {
+            let function = tool_calls.into_iter().next().unwrap();
+            message.function_call = Some(ChatMessageFunctionCall {
+                name: function.name,
+                arguments: function.arguments,
+            });
+            (message, FinishReason::function_call)
+        }
+        false => {
+            message.tool_calls = Some(
+                tool_calls
+                    .into_iter()
+                    .map(|function| ToolCall {
+                        index: None,
+                        id: format!("call_{}", uuid::Uuid::new_v4()),
+                        ty: String::from("function"),
+                        function,
+                    })
+                    .collect(),
+            );
+            (message, FinishReason::tool_calls)
+        }
+    }
+}
+
+/// The messages sent from the job generating a streamed completion or chat completion to the stream of the response.
+enum Generated {
+    /// A token of the choice generated by the model.
+    Token { index: u32, token: String },
+    /// The generation of the choice ends for the reason, and the next choice follows.
+    Stopped {
+        index: u32,
+        finish_reason: FinishReason,
+    },
+    /// The generation of the last choice ends for the reason, with the usage statistics of all choices, and the number of the messages dropped to fit the prompt into the context window if any.
+    Finished {
+        index: u32,
+        finish_reason: FinishReason,
+        usage: Usage,
+        truncated_messages: Option<u32>,
+    },
+}
+
+/// Generates the tokens of a streamed chat completion with the given execution context, and sends them through the channel. The oldest turns are dropped if the conversation does not fit into the context window.
+async fn stream_tokens(
+    graph: &mut Graph,
+    sampling: &SamplingOptions,
+    template: &ChatPrompt,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    tools: &[Tool],
+    stops: &[String],
+    sender: TokenSender,
+) {
+    // drop the oldest turns if the conversation does not fit into the context window
+    let fitted = match fit_context(graph, template, messages, tools, sampling.max_tokens) {
+        Ok(fitted) => fitted,
+        Err(err) => {
+            let _ = sender.send(Err(err)).await;
+            return;
+        }
+    };
+
+    let truncated_messages =
+        (fitted.dropped_messages > 0).then_some(fitted.dropped_messages as u32);
+    stream_choices(
+        graph,
+        sampling,
+        &fitted.prompt,
+        false,
+        stops,
+        truncated_messages,
+        sender,
+    )
+    .await;
+}
+
+/// Generates the tokens of the choices of the prompt with the given execution context, and sends them through the channel. The choices are generated one after another, and each begins with the prompt if `echo` is set. The channel is closed when the generation ends, or right after an error is sent, and the generation stops if the completion is cancelled.
+async fn stream_choices(
+    graph: &mut Graph,
+    sampling: &SamplingOptions,
+    prompt: &str,
+    echo: bool,
+    stops: &[String],
+    truncated_messages: Option<u32>,
+    sender: TokenSender,
+) {
+    // apply the sampling parameters of the request
+    let metadata = match sampling.to_metadata(&graph.metadata) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            let _ = sender.send(Err(err)).await;
+            return;
+        }
+    };
+    if let Err(msg) = graph.update_metadata(&metadata) {
+        let _ = sender.send(Err(ApiError::Internal(msg))).await;
+        return;
+    }
+
+    let choices = sampling.choices();
+    // the prompt tokens are the same for all choices
+    let mut prompt_tokens;
+    let mut completion_tokens = 0;
+    for index in 0..choices as u32 {
+        if echo {
+            let token = String::from(prompt);
+            if sender
+                .send(Ok(Generated::Token { index, token }))
+                .await
                 .is_err()
             {
-                return error::internal_server_error(String::from(
-                    "Fail to set input tensor while computing the number of tokens.",
-                ));
-            };
+                break;
+            }
+        }
 
-            // get the number of output tokens
-            let mut token_info_buffer = vec![0u8; *MAX_BUFFER_SIZE.get().unwrap()];
-            let mut size_token_info = graph.get_output(1, &mut token_info_buffer).unwrap();
-            size_token_info = std::cmp::min(*MAX_BUFFER_SIZE.get().unwrap(), size_token_info);
-            let token_info: Value =
-                serde_json::from_slice(&token_info_buffer[..size_token_info]).unwrap();
-            let completion_tokens = token_info["output_tokens"].as_i64().unwrap() as u32;
-
-            // create ChatCompletionResponse
-            let chat_completion_obejct = ChatCompletionObject {
-                id: uuid::Uuid::new_v4().to_string(),
-                object: String::from("chat.completion"),
-                created: SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                model: chat_request.model.clone().unwrap_or_default(),
-                choices: vec![ChatCompletionObjectChoice {
-                    index: 0,
-                    message: ChatCompletionObjectMessage {
-                        role: ChatCompletionRole::Assistant,
-                        content: message,
-                        function_call: None,
-                    },
-                    finish_reason: FinishReason::stop,
-                }],
+        let result = generate_tokens(graph, prompt, index, stops, &metadata, &sender).await;
+
+        // reset the execution context for the next choice or completion
+        if let Err(e) = graph.finish_single() {
+            println!("Error: Fail to reset the execution context: {e}");
+        }
+
+        let finish_reason = match result {
+            Ok(Some(choice)) => {
+                prompt_tokens = choice.prompt_tokens;
+                completion_tokens += choice.completion_tokens;
+                choice.finish_reason
+            }
+            // the client has gone, or the completion is cancelled by its id
+            Ok(None) => {
+                let _ = sender.send(Err(ApiError::Cancelled)).await;
+                break;
+            }
+            Err(err) => {
+                println!("Error: {}", &err);
+                let _ = sender.send(Err(err)).await;
+                break;
+            }
+        };
+
+        let generated = match index + 1 == choices as u32 {
+            true => Generated::Finished {
+                index,
+                finish_reason,
                 usage: Usage {
                     prompt_tokens,
                     completion_tokens,
                     total_tokens: prompt_tokens + completion_tokens,
                 },
-            };
+                truncated_messages,
+            },
+            false => Generated::Stopped {
+                index,
+                finish_reason,
+            },
+        };
+        if sender.send(Ok(generated)).await.is_err() {
+            break;
+        }
+    }
 
-            // return response
-            Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .body(Body::from(
-                    serde_json::to_string(&chat_completion_obejct).unwrap(),
-                ))
+    // restore the metadata no matter whether the generation succeeds
+    if let Err(msg) = graph.reset_metadata() {
+        println!("Error: {}", &msg);
+    }
+}
+
+/// Computes the tokens of the choice one by one and sends their text through the channel until a stop sequence is found. The stop sequence and the text after it are not sent, and the generation is cut after `n-predict` tokens. Returns the numbers of tokens of the choice and why its generation ends, or `None` if the client has gone or the completion is cancelled.
+async fn generate_tokens(
+    graph: &mut Graph,
+    prompt: &str,
+    index: u32,
+    stops: &[String],
+    metadata: &Metadata,
+    sender: &TokenSender,
+) -> Result<Option<GeneratedChoice>, ApiError> {
+    // set input
+    set_prompt(graph, prompt)?;
+
+    // get the number of input tokens
+    let prompt_tokens = get_token_info(graph)
+        .map_err(ApiError::Internal)?
+        .input_tokens;
+
+    // the bytes of a character split across tokens are held back until the character is complete
+    let mut decoder = Utf8Decoder::new();
+    // the text which may begin a stop sequence is held back until the following tokens tell whether it does
+    let mut matcher = StopMatcher::new(stops);
+
+    // each computation generates exactly one token
+    let mut completion_tokens = 0;
+    loop {
+        if sender.is_cancelled() {
+            return Ok(None);
+        }
+
+        let (text, finish_reason) = match compute_next_token(graph).map_err(ApiError::Internal)? {
+            NextToken::Token(token) => {
+                completion_tokens += 1;
+                let mut text = matcher.push(&decoder.push(&token));
+                if matcher.stopped() {
+                    (text, Some(FinishReason::stop))
+                } else if completion_tokens as u64 >= metadata.n_predict {
+                    // the text held back is sent, since the answer is cut before any stop sequence completes
+                    text.push_str(&matcher.push(&decoder.flush()));
+                    text.push_str(&matcher.flush());
+                    (text, Some(FinishReason::length))
+                } else {
+                    (text, None)
+                }
+            }
+            // send the text held back when the generation ends
+            NextToken::End(finish_reason) => {
+                let mut text = matcher.push(&decoder.flush());
+                text.push_str(&matcher.flush());
+                (text, Some(finish_reason))
+            }
+        };
+
+        if !text.is_empty() {
+            // stop generating if the client has gone
+            if sender
+                .send(Ok(Generated::Token { index, token: text }))
+                .await
+                .is_err()
+            {
+                return Ok(None);
+            }
+        }
+        if let Some(finish_reason) = finish_reason {
+            return Ok(Some(GeneratedChoice {
+                prompt_tokens,
+                completion_tokens,
+                finish_reason,
+            }));
         }
+
+        // let the server send the token before computing the next one
+        tokio::task::yield_now().await;
+    }
+}
+
+/// The channel through which a job sends the tokens of a streamed completion. The job keeps the registration of the completion, so that the completion can be cancelled by its id until the job ends.
+struct TokenSender {
+    sender: mpsc::Sender<Result<Generated, ApiError>>,
+    registration: Registration,
+}
+impl TokenSender {
+    async fn send(
+        &self,
+        generated: Result<Generated, ApiError>,
+    ) -> Result<(), mpsc::error::SendError<Result<Generated, ApiError>>> {
+        self.sender.send(generated).await
+    }
+
+    /// Whether the generation should stop, because the client has gone or the completion is cancelled by its id.
+    fn is_cancelled(&self) -> bool {
+        self.sender.is_closed() || self.registration.flag().is_cancelled()
+    }
+}
+
+/// The numbers of tokens of a streamed choice, and the reason its generation ends.
+struct GeneratedChoice {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    finish_reason: FinishReason,
+}
+
+/// Fits the prompt of the conversation into the context window of the model, leaving room for the answer of `max_tokens` tokens, or of the default number of tokens to predict but at most half of the context window. The oldest turns are dropped from the messages if the conversation is too long.
+fn fit_context(
+    graph: &mut Graph,
+    template: &ChatPrompt,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    tools: &[Tool],
+    max_tokens: Option<i64>,
+) -> Result<FittedPrompt, ApiError> {
+    let ctx_size = graph.metadata.ctx_size;
+    let reserved = match max_tokens {
+        Some(max_tokens) => max_tokens as u64,
+        None => std::cmp::min(graph.metadata.n_predict, ctx_size / 2),
     };
+    if reserved >= ctx_size {
+        return Err(ApiError::InvalidParameter {
+            param: String::from("max_tokens"),
+            message: format!(
+                "`max_tokens` must be less than the context size {ctx_size}, but got {reserved}"
+            ),
+        });
+    }
 
-    match result {
-        Ok(response) => Ok(response),
-        Err(e) => error::internal_server_error(e.to_string()),
+    let fitted = budget::fit_prompt(
+        template,
+        messages,
+        tools,
+        (ctx_size - reserved) as usize,
+        |prompt| count_tokens(graph, prompt),
+    )?;
+    if fitted.dropped_messages > 0 {
+        println!(
+            "[INFO] Drop the {n} oldest messages to fit the prompt into the context window",
+            n = fitted.dropped_messages
+        );
     }
+
+    Ok(fitted)
 }
 
-/// Runs inference on the model with the given name and returns the output.
-pub(crate) async fn infer(prompt: impl AsRef<str>) -> std::result::Result<Vec<u8>, String> {
-    let mut graph = crate::GRAPH.get().unwrap().lock().unwrap();
+/// The post-processed answer of a choice of a chat completion, and the reason its generation ends.
+struct ChatAnswer {
+    text: String,
+    finish_reason: FinishReason,
+}
 
-    // set input
-    let tensor_data = prompt.as_ref().as_bytes().to_vec();
-    if graph
-        .set_input(0, wasi_nn::TensorType::U8, &[1], &tensor_data)
-        .is_err()
-    {
-        return Err(String::from("Fail to set input tensor"));
+/// Runs the inference of the choices of a chat completion one after another. Returns the post-processed answers, the number of prompt tokens and the total number of completion tokens.
+async fn run_chat_choices(
+    graph: &mut Graph,
+    prompt: &str,
+    template: &ChatPrompt,
+    stops: &[String],
+    metadata: &Metadata,
+    choices: usize,
+    cancel: &CancelFlag,
+) -> Result<(Vec<ChatAnswer>, u32, u32), ApiError> {
+    graph
+        .update_metadata(metadata)
+        .map_err(ApiError::Internal)?;
+
+    let mut answers = Vec::with_capacity(choices);
+    let (mut prompt_tokens, mut completion_tokens) = (0, 0);
+    for _ in 0..choices {
+        let (answer, input_tokens, output_tokens) =
+            run_chat_completion(graph, prompt, template, stops, metadata, cancel).await?;
+        answers.push(answer);
+        prompt_tokens = input_tokens;
+        completion_tokens += output_tokens;
+    }
+
+    Ok((answers, prompt_tokens, completion_tokens))
+}
+
+/// Runs the inference of a chat completion, and returns the post-processed answer and the number of prompt tokens and completion tokens.
+async fn run_chat_completion(
+    graph: &mut Graph,
+    prompt: &str,
+    template: &ChatPrompt,
+    stops: &[String],
+    metadata: &Metadata,
+    cancel: &CancelFlag,
+) -> Result<(ChatAnswer, u32, u32), ApiError> {
+    // execute the inference
+    let Inference {
+        output,
+        token_info,
+        finish_reason,
+    } = run_inference(graph, prompt, metadata, cancel).await?;
+    let prompt_tokens = token_info.input_tokens;
+    // all the tokens generated are counted, including the ones after a stop sequence
+    let completion_tokens = token_info.output_tokens;
+
+    // convert inference result to string
+    let output = String::from_utf8_lossy(&output);
+    // the answer ends at the stop sequence even if the generation goes on after it
+    let kept = truncate_at_stop(&output, stops);
+    let finish_reason = match kept.len() < output.len() {
+        true => FinishReason::stop,
+        false => finish_reason,
+    };
+    // post-process
+    let message = template.post_process(kept);
+
+    let answer = ChatAnswer {
+        text: message,
+        finish_reason,
     };
+    Ok((answer, prompt_tokens, completion_tokens))
+}
+
+/// Computes the answer token by token with the given execution context. The generation yields to the other requests between two tokens, and stops once the completion is cancelled. The answer is cut if the context window is full, or after `n-predict` tokens.
+async fn compute_answer(
+    graph: &mut Graph,
+    metadata: &Metadata,
+    cancel: &CancelFlag,
+) -> Result<Inference, String> {
+    let mut output = vec![];
+    let mut token_info = TokenInfo::default();
+    loop {
+        if cancel.is_cancelled() {
+            return Err(String::from("The completion is cancelled"));
+        }
+
+        let finish_reason = match compute_next_token(graph)? {
+            NextToken::Token(token) => {
+                output.extend_from_slice(&token);
+                token_info.output_tokens += 1;
+                match token_info.output_tokens as u64 >= metadata.n_predict {
+                    true => Some(FinishReason::length),
+                    false => None,
+                }
+            }
+            NextToken::End(finish_reason) => Some(finish_reason),
+        };
+
+        if let Some(finish_reason) = finish_reason {
+            return Ok(Inference {
+                output,
+                token_info,
+                finish_reason,
+            });
+        }
+
+        // let the server handle the other requests, e.g. the cancellation of this one, before computing the next token
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Truncates the output at the first occurrence of any of the stop sequences.
+fn truncate_at_stop<'a>(output: &'a str, stops: &[String]) -> &'a str {
+    let end = stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| output.find(stop.as_str()))
+        .min()
+        .unwrap_or(output.len());
+    &output[..end]
+}
+
+/// The sampling parameters of a chat-completion or completion request.
+#[derive(Debug, Default)]
+struct SamplingOptions {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<i64>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    stop: Option<Vec<String>>,
+    /// Whether the request biases the logits of any token.
+    logit_bias: bool,
+    grammar: Option<String>,
+    n: Option<i64>,
+    best_of: Option<i64>,
+    logprobs: bool,
+    top_logprobs: Option<i64>,
+}
+impl From<&ChatCompletionRequest> for SamplingOptions {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        Self {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            max_tokens: request.max_tokens.map(i64::from),
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            stop: request.stop.clone(),
+            logit_bias: request
+                .logit_bias
+                .as_ref()
+                .is_some_and(|logit_bias| !logit_bias.is_empty()),
+            grammar: request.response_format.as_ref().and_then(grammar::grammar),
+            n: request.n_choice.map(i64::from),
+            best_of: None,
+            logprobs: request.logprobs.unwrap_or(false),
+            top_logprobs: request.top_logprobs.map(i64::from),
+        }
+    }
+}
+impl From<&CompletionRequest> for SamplingOptions {
+    fn from(request: &CompletionRequest) -> Self {
+        Self {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            max_tokens: request.max_tokens.map(i64::from),
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            stop: request.stop.clone(),
+            logit_bias: request
+                .logit_bias
+                .as_ref()
+                .is_some_and(|logit_bias| !logit_bias.is_empty()),
+            grammar: None,
+            n: request.n.map(i64::from),
+            best_of: request.best_of.map(i64::from),
+            logprobs: request.logprobs.is_some(),
+            top_logprobs: request.logprobs.map(i64::from),
+        }
+    }
+}
+impl SamplingOptions {
+    /// The maximum number of stop sequences in a request.
+    const MAX_STOP_SEQUENCES: usize = 4;
+    /// The maximum number of choices generated for a request.
+    const MAX_CHOICES: i64 = 20;
+
+    /// Checks if the sampling parameters are in the valid ranges.
+    fn validate(&self) -> Result<(), ApiError> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("temperature"),
+                    message: format!(
+                        "`temperature` must be between 0 and 2, but got {temperature}"
+                    ),
+                });
+            }
+        }
+
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("top_p"),
+                    message: format!("`top_p` must be between 0 and 1, but got {top_p}"),
+                });
+            }
+        }
+
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens < 1 {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("max_tokens"),
+                    message: format!("`max_tokens` must be no less than 1, but got {max_tokens}"),
+                });
+            }
+        }
+
+        if let Some(n) = self.n {
+            if !(1..=Self::MAX_CHOICES).contains(&n) {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("n"),
+                    message: format!(
+                        "`n` must be between 1 and {max}, but got {n}",
+                        max = Self::MAX_CHOICES
+                    ),
+                });
+            }
+        }
+
+        // the backend does not report the log-probabilities of the generated tokens, by which the candidates would be ranked
+        if let Some(best_of) = self.best_of {
+            let n = self.n.unwrap_or(1);
+            if best_of != n {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("best_of"),
+                    message: format!(
+                        "`best_of` other than `n` ({n}) is not supported by the backend, which does not report the log-probabilities to rank the candidates, but got {best_of}"
+                    ),
+                });
+            }
+        }
+
+        // the backend does not report the log-probabilities of the tokens
+        if self.logprobs || self.top_logprobs.is_some() {
+            let param = match self.logprobs {
+                true => "logprobs",
+                false => "top_logprobs",
+            };
+            return Err(ApiError::InvalidParameter {
+                param: String::from(param),
+                message: format!(
+                    "`{param}` is not supported by the backend, which does not report the log-probabilities of the tokens"
+                ),
+            });
+        }
+
+        // the backend samples without the penalties and the logit biases of the OpenAI API, so they are rejected rather than ignored
+        for (param, penalty) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if penalty.is_some_and(|penalty| penalty != 0.0) {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from(param),
+                    message: format!(
+                        "`{param}` is not supported by the backend. Use `--repeat-penalty` of the server to penalize the repeated tokens"
+                    ),
+                });
+            }
+        }
+        if self.logit_bias {
+            return Err(ApiError::InvalidParameter {
+                param: String::from("logit_bias"),
+                message: String::from("`logit_bias` is not supported by the backend"),
+            });
+        }
+
+        if let Some(stop) = &self.stop {
+            if stop.len() > Self::MAX_STOP_SEQUENCES {
+                return Err(ApiError::InvalidParameter {
+                    param: String::from("stop"),
+                    message: format!(
+                        "`stop` accepts up to {max} sequences, but got {n}",
+                        max = Self::MAX_STOP_SEQUENCES,
+                        n = stop.len()
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the metadata of the request by overriding the given metadata with the validated sampling parameters. Fails if several choices are requested with the temperature 0, since the greedy sampling would generate the same choice again and again.
+    fn to_metadata(&self, base: &Metadata) -> Result<Metadata, ApiError> {
+        let mut metadata = base.clone();
+        if let Some(temperature) = self.temperature {
+            metadata.temp = temperature;
+        }
+        if let Some(top_p) = self.top_p {
+            metadata.top_p = top_p;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            metadata.n_predict = max_tokens as u64;
+        }
+        if let Some(grammar) = &self.grammar {
+            metadata.grammar = Some(grammar.clone());
+        }
+
+        if self.choices() > 1 && metadata.temp == 0.0 {
+            return Err(ApiError::InvalidParameter {
+                param: String::from("n"),
+                message: format!(
+                    "`n` greater than 1 requires a temperature above 0, since the choices would be the same, but got {n}",
+                    n = self.choices()
+                ),
+            });
+        }
+
+        Ok(metadata)
+    }
+
+    /// The number of choices returned for the request.
+    fn choices(&self) -> usize {
+        self.n.unwrap_or(1) as usize
+    }
+
+    /// Returns the stop sequences of the request, followed by the given ones of the server.
+    fn stops(&self, reverse_prompts: &[String]) -> Vec<String> {
+        let mut stops = self.stop.clone().unwrap_or_default();
+        stops.extend(reverse_prompts.iter().cloned());
+        stops
+    }
+}
+
+/// The states of a streamed completion or chat completion.
+enum StreamState {
+    /// No chunk of the current choice has been sent yet.
+    Generating,
+    /// At least one chunk has been sent.
+    Started,
+    /// The last chunk carrying the finish reason has been sent.
+    Finished,
+    /// The `[DONE]` message has been sent, or an error occurred.
+    Ended,
+}
+
+/// Formats a chunk as a server-sent event.
+fn sse_event(chunk: &impl Serialize) -> String {
+    match serde_json::to_string(chunk) {
+        Ok(json) => format!("data: {json}\n\n"),
+        Err(e) => sse_error(format!("Fail to serialize chunk: {msg}", msg = e)),
+    }
+}
+
+/// Formats an internal error as a server-sent event. The event is the last one of the stream, so the client can tell a failed completion from a finished one.
+fn sse_error(msg: impl AsRef<str>) -> String {
+    sse_api_error(ApiError::Internal(msg.as_ref().to_string()))
+}
+
+/// Formats an error as a server-sent event, which is the last one of the stream.
+fn sse_api_error(err: ApiError) -> String {
+    println!("[ERROR] {}", &err);
+    format!("data: {json}\n\n", json = err.to_json())
+}
+
+/// The result of computing the next token.
+enum NextToken {
+    /// The bytes of the token generated by the model, which may end in the middle of a UTF-8 character.
+    Token(Vec<u8>),
+    /// The generation ends for the reason without a token, because the model ends the answer or the context window is full.
+    End(FinishReason),
+}
+
+/// Computes the next token with the given execution context.
+fn compute_next_token(graph: &mut Graph) -> Result<NextToken, String> {
+    match graph.compute_single() {
+        Ok(_) => {
+            // Retrieve the output.
+            match graph.get_output_single(0) {
+                Ok(output_buffer) => Ok(NextToken::Token(output_buffer)),
+                Err(e) => Err(format!("Fail to get output tensor: {msg}", msg = e)),
+            }
+        }
+        Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::EndOfSequence)) => {
+            Ok(NextToken::End(FinishReason::stop))
+        }
+        Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::ContextFull)) => {
+            Ok(NextToken::End(FinishReason::length))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Runs inference on the model with the given name and returns the output. The inference stops once the completion is cancelled.
+async fn infer(
+    model_name: impl AsRef<str>,
+    prompt: impl AsRef<str>,
+    sampling: SamplingOptions,
+    cancel: CancelFlag,
+) -> std::result::Result<Vec<Inference>, ApiError> {
+    let prompt = prompt.as_ref().to_string();
+    scheduler(model_name)?
+        .run_async(move |graph| {
+            Box::pin(async move {
+                // apply the sampling parameters of the request
+                let metadata = sampling.to_metadata(&graph.metadata)?;
+
+                let result =
+                    run_choices(graph, &prompt, &metadata, sampling.choices(), &cancel).await;
+
+                // restore the metadata no matter whether the inference succeeds
+                graph.reset_metadata().map_err(ApiError::Internal)?;
+
+                result
+            })
+        })
+        .await?
+}
+
+/// Runs the inference of the choices one after another.
+async fn run_choices(
+    graph: &mut Graph,
+    prompt: &str,
+    metadata: &Metadata,
+    choices: usize,
+    cancel: &CancelFlag,
+) -> Result<Vec<Inference>, ApiError> {
+    graph
+        .update_metadata(metadata)
+        .map_err(ApiError::Internal)?;
+
+    let mut outputs = Vec::with_capacity(choices);
+    for _ in 0..choices {
+        outputs.push(run_inference(graph, prompt, metadata, cancel).await?);
+    }
+
+    Ok(outputs)
+}
+
+/// A completion generated for a prompt, with the numbers of tokens of the prompt and the output, and the reason the generation ends.
+struct Inference {
+    output: Vec<u8>,
+    token_info: TokenInfo,
+    finish_reason: FinishReason,
+}
+
+/// Runs inference with the given prompt and returns the output with the numbers of tokens of the prompt and the output, and the reason the generation ends. The execution context is reset afterwards, even if the inference fails or is cancelled.
+async fn run_inference(
+    graph: &mut Graph,
+    prompt: impl AsRef<str>,
+    metadata: &Metadata,
+    cancel: &CancelFlag,
+) -> Result<Inference, ApiError> {
+    // set input
+    set_prompt(graph, prompt.as_ref())?;
+
+    // get the number of input tokens
+    let input_tokens = get_token_info(graph)
+        .map_err(ApiError::Internal)?
+        .input_tokens;
 
     // execute the inference
-    if graph.compute().is_err() {
-        return Err(String::from("Fail to execute model inference"));
+    let result = compute_answer(graph, metadata, cancel).await;
+    if let Err(e) = graph.finish_single() {
+        return Err(ApiError::Internal(format!(
+            "Fail to reset the execution context: {msg}",
+            msg = e
+        )));
     }
 
-    // Retrieve the output.
-    let mut output_buffer = vec![0u8; *MAX_BUFFER_SIZE.get().unwrap()];
-    let mut output_size = match graph.get_output(0, &mut output_buffer) {
-        Ok(size) => size,
+    let mut inference = result.map_err(ApiError::Internal)?;
+    inference.token_info.input_tokens = input_tokens;
+    Ok(inference)
+}
+
+/// The numbers of tokens of the last computation, which are reported by the backend as the output tensor at index 1.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct TokenInfo {
+    /// The number of tokens of the input tensor.
+    input_tokens: u32,
+    /// The number of tokens generated by the computation.
+    output_tokens: u32,
+}
+
+/// Retrieves the numbers of tokens from the execution context.
+fn get_token_info(graph: &Graph) -> Result<TokenInfo, String> {
+    let token_info_buffer = match graph.get_output(1) {
+        Ok(token_info_buffer) => token_info_buffer,
+        Err(e) => {
+            return Err(format!("Fail to get token info: {msg}", msg = e));
+        }
+    };
+    let token_info: Value = match serde_json::from_slice(&token_info_buffer) {
+        Ok(token_info) => token_info,
         Err(e) => {
-            return Err(format!(
-                "Fail to get output tensor: {msg}",
-                msg = e.to_string()
-            ))
+            return Err(format!("Fail to parse token info: {msg}", msg = e));
         }
     };
-    output_size = std::cmp::min(*MAX_BUFFER_SIZE.get().unwrap(), output_size);
 
-    Ok(output_buffer[..output_size].to_vec())
+    Ok(TokenInfo {
+        input_tokens: token_info["input_tokens"].as_u64().unwrap_or_default() as u32,
+        output_tokens: token_info["output_tokens"].as_u64().unwrap_or_default() as u32,
+    })
 }
 
-fn post_process(output: impl AsRef<str>, template_ty: PromptTemplateType) -> String {
-    if template_ty == PromptTemplateType::Baichuan2 {
-        if output.as_ref().contains("用户:") {
-            output.as_ref().trim_end_matches("用户:").trim().to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_param(options: SamplingOptions) -> String {
+        match options.validate() {
+            Err(ApiError::InvalidParameter { param, .. }) => param,
+            result => panic!("unexpected result: {result:?}"),
         }
-    } else if template_ty == PromptTemplateType::OpenChat {
-        if output.as_ref().contains("<|end_of_turn|>") {
-            output
-                .as_ref()
-                .trim_end_matches("<|end_of_turn|>")
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::ChatML {
-        if output.as_ref().contains("<|im_start|>") && output.as_ref().contains("<|im_end|>") {
-            let idx_start = output.as_ref().find("<|im_start|>").unwrap();
-            let idx_end = output.as_ref().find("<|im_end|>").unwrap();
-
-            match idx_start <= idx_end {
-                true => output.as_ref().split("<|im_start|>").collect::<Vec<_>>()[0]
-                    .trim()
-                    .to_owned(),
-                false => output.as_ref().split("<|im_end|>").collect::<Vec<_>>()[0]
-                    .trim()
-                    .to_owned(),
-            }
-        } else if output.as_ref().contains("<|im_start|>") {
-            output.as_ref().split("<|im_start|>").collect::<Vec<_>>()[0]
-                .trim()
-                .to_owned()
-        } else if output.as_ref().contains("<|im_end|>") {
-            output.as_ref().split("<|im_end|>").collect::<Vec<_>>()[0]
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::Zephyr
-        || template_ty == PromptTemplateType::MistralLite
-    {
-        if output.as_ref().contains("</s><") {
-            output.as_ref().trim_end_matches("</s><").trim().to_owned()
-        } else if output.as_ref().contains("</s>") {
-            output
-                .as_ref()
-                .strip_suffix("</s>")
-                .unwrap()
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::DeepseekChat {
-        if output.as_ref().contains("<|end_of_sentence|>") {
-            output
-                .as_ref()
-                .trim_end_matches("<|end_of_sentence|>")
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::BelleLlama2Chat {
-        if output.as_ref().contains("Human:") {
-            output.as_ref().trim_end_matches("Human:").trim().to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else {
-        output.as_ref().trim().to_owned()
+    }
+
+    #[test]
+    fn reject_out_of_range_parameters() {
+        let options = SamplingOptions {
+            temperature: Some(0.0),
+            top_p: Some(1.0),
+            max_tokens: Some(1),
+            n: Some(SamplingOptions::MAX_CHOICES),
+            stop: Some(vec![
+                String::from("\n");
+                SamplingOptions::MAX_STOP_SEQUENCES
+            ]),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+
+        let options = SamplingOptions {
+            temperature: Some(2.5),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "temperature");
+        let options = SamplingOptions {
+            top_p: Some(-0.1),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "top_p");
+        let options = SamplingOptions {
+            max_tokens: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "max_tokens");
+        let options = SamplingOptions {
+            n: Some(SamplingOptions::MAX_CHOICES + 1),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "n");
+        let options = SamplingOptions {
+            stop: Some(vec![
+                String::from("\n");
+                SamplingOptions::MAX_STOP_SEQUENCES + 1
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "stop");
+    }
+
+    #[test]
+    fn reject_unsupported_parameters() {
+        // the defaults of the OpenAI clients are accepted
+        let options = SamplingOptions {
+            presence_penalty: Some(0.0),
+            frequency_penalty: Some(0.0),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+
+        let options = SamplingOptions {
+            presence_penalty: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "presence_penalty");
+        let options = SamplingOptions {
+            frequency_penalty: Some(-1.0),
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "frequency_penalty");
+        let options = SamplingOptions {
+            logit_bias: true,
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "logit_bias");
+        let options = SamplingOptions {
+            logprobs: true,
+            ..Default::default()
+        };
+        assert_eq!(invalid_param(options), "logprobs");
+    }
+
+    #[test]
+    fn override_metadata() {
+        let base = Metadata {
+            temp: 0.8,
+            top_p: 0.9,
+            n_predict: 1024,
+            ..Default::default()
+        };
+        let options = SamplingOptions {
+            temperature: Some(0.2),
+            max_tokens: Some(16),
+            grammar: Some(String::from("root ::= \"yes\" | \"no\"")),
+            ..Default::default()
+        };
+        let metadata = options.to_metadata(&base).unwrap();
+
+        assert_eq!(metadata.temp, 0.2);
+        assert_eq!(metadata.top_p, 0.9);
+        assert_eq!(metadata.n_predict, 16);
+        assert_eq!(
+            metadata.grammar.as_deref(),
+            Some("root ::= \"yes\" | \"no\"")
+        );
+
+        // the metadata of the server is left as it is
+        assert_eq!(base.temp, 0.8);
+        assert_eq!(base.grammar, None);
+    }
+
+    #[test]
+    fn reject_choices_of_greedy_sampling() {
+        let base = Metadata {
+            temp: 0.0,
+            ..Default::default()
+        };
+        let options = SamplingOptions {
+            n: Some(2),
+            ..Default::default()
+        };
+        match options.to_metadata(&base) {
+            Err(ApiError::InvalidParameter { param, .. }) => assert_eq!(param, "n"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        // the temperature of the request overrides the one of the server
+        let options = SamplingOptions {
+            temperature: Some(0.7),
+            n: Some(2),
+            ..Default::default()
+        };
+        assert!(options.to_metadata(&base).is_ok());
+        let options = SamplingOptions {
+            n: Some(1),
+            ..Default::default()
+        };
+        assert!(options.to_metadata(&base).is_ok());
+    }
+
+    #[test]
+    fn null_content_of_tool_calls() {
+        let call = ToolCallFunction {
+            name: String::from("get_weather"),
+            arguments: String::from("{\"city\":\"Paris\"}"),
+        };
+        let (message, finish_reason) =
+            assistant_message(String::new(), vec![call], false, FinishReason::stop);
+        let json = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(finish_reason, FinishReason::tool_calls);
+        assert!(json["content"].is_null());
+        assert_eq!(json["tool_calls"][0]["function"]["name"], "get_weather");
+
+        let (message, finish_reason) =
+            assistant_message(String::new(), vec![], false, FinishReason::length);
+        assert_eq!(finish_reason, FinishReason::length);
+        assert_eq!(message.content.as_deref(), Some(""));
     }
 }
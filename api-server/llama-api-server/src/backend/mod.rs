@@ -1,25 +1,23 @@
 pub(crate) mod ggml;
 
 use crate::{error, ModelInfo};
-use chat_prompts::PromptTemplateType;
 use hyper::{Body, Request, Response};
 
 pub(crate) async fn handle_llama_request(
     req: Request<Body>,
-    model_info: ModelInfo,
-    template_ty: PromptTemplateType,
+    models: Vec<ModelInfo>,
     created: u64,
     log_prompts: bool,
-    stream: bool,
-    stop: Option<String>,
+    stops: Vec<String>,
 ) -> Result<Response<Body>, hyper::Error> {
     match req.uri().path() {
         "/v1/chat/completions" => {
-            ggml::chat_completions_handler(req, template_ty, log_prompts, stream, stop).await
+            ggml::chat_completions_handler(req, models, log_prompts, stops).await
         }
-        "/v1/completions" => ggml::completions_handler(req).await,
-        // "/v1/embeddings" => ggml::_embeddings_handler().await,
-        "/v1/models" => ggml::models_handler(model_info, template_ty, created).await,
+        "/v1/completions" => ggml::completions_handler(req, models).await,
+        "/v1/cancel" => ggml::cancel_handler(req).await,
+        "/v1/embeddings" => ggml::embeddings_handler(req, models).await,
+        "/v1/models" => ggml::models_handler(models, created).await,
         _ => error::invalid_endpoint(req.uri().path()),
     }
 }
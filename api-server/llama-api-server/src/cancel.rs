@@ -0,0 +1,75 @@
+//! Cancels the running completions. A completion is cancelled when its request is dropped, e.g. because the client has gone, or when it is cancelled by its id. The job generating the completion checks its flag between tokens, so the execution context is given back as soon as possible.
+
+use crate::error::ApiError;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The flags of the running completions, keyed by their ids.
+static RUNNING: Lazy<Mutex<HashMap<String, CancelFlag>>> = Lazy::new(Default::default);
+
+/// Tells the job generating a completion whether to stop.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancelFlag(Arc<AtomicBool>);
+impl CancelFlag {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A running completion, which can be cancelled by its id until the registration is dropped. Dropping the registration cancels the completion too, so it is kept by the request, or by the job of a streamed completion whose client is watched through the channel of the stream.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: String,
+    flag: CancelFlag,
+}
+impl Registration {
+    /// Registers the completion with the given id. Fails if a running completion has the same id.
+    pub(crate) fn new(id: impl Into<String>) -> Result<Self, ApiError> {
+        let id = id.into();
+        let mut running = RUNNING.lock().unwrap();
+        if running.contains_key(&id) {
+            return Err(ApiError::BadRequest(format!(
+                "A completion with the id `{id}` is already running"
+            )));
+        }
+
+        let flag = CancelFlag::default();
+        running.insert(id.clone(), flag.clone());
+        Ok(Self { id, flag })
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub(crate) fn flag(&self) -> CancelFlag {
+        self.flag.clone()
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.flag.cancel();
+        RUNNING.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Cancels the running completion with the given id. Returns `false` if there is no such completion.
+pub(crate) fn cancel(id: &str) -> bool {
+    match RUNNING.lock().unwrap().get(id) {
+        Some(flag) => {
+            flag.cancel();
+            true
+        }
+        None => false,
+    }
+}
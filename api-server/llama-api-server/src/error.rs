@@ -1,34 +1,198 @@
-use hyper::{Body, Response};
+use chat_prompts::error::PromptError;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
 use thiserror::Error;
 
-#[allow(dead_code)]
-pub(crate) fn not_implemented() -> Result<Response<Body>, hyper::Error> {
-    let mut response = Response::new(Body::from("501 Not Implemented"));
-    *response.status_mut() = hyper::StatusCode::NOT_IMPLEMENTED;
-    Ok(response)
+/// The number of seconds suggested by the `Retry-After` header of the responses to the requests which are rejected temporarily.
+const RETRY_AFTER_SECS: u64 = 5;
+/// The status code of the responses to the cancelled completions, which is the one nginx uses for the requests closed by the client.
+const STATUS_CANCELLED: u16 = 499;
+
+/// The errors returned by the API endpoints. Each error is serialized in the OpenAI error format, i.e. `{"error": {"message", "type", "param", "code"}}`.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ApiError {
+    #[error("Invalid request body: {0}")]
+    InvalidJson(String),
+    #[error("{message}")]
+    InvalidParameter { param: String, message: String },
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("The requested model is not found: {0}")]
+    ModelNotFound(String),
+    #[error("The requested service endpoint is not found: {0}")]
+    EndpointNotFound(String),
+    #[error("No running completion has the id: {0}")]
+    CompletionNotFound(String),
+    #[error("The completion is cancelled.")]
+    Cancelled,
+    #[error("Fail to build the prompt: {0}")]
+    Prompt(#[from] PromptError),
+    #[error("The prompt exceeds the context window of the model.")]
+    ContextLengthExceeded,
+    #[error("Too many requests are waiting for the model. Please retry later.")]
+    QueueFull,
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("The output of the model does not match the response format: {0}")]
+    InvalidOutput(String),
+    #[error("Internal server error: {0}")]
+    Internal(String),
+}
+impl ApiError {
+    /// The HTTP status code of the error.
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidJson(_)
+            | ApiError::InvalidParameter { .. }
+            | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::ModelNotFound(_)
+            | ApiError::EndpointNotFound(_)
+            | ApiError::CompletionNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Cancelled => {
+                StatusCode::from_u16(STATUS_CANCELLED).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            ApiError::Prompt(PromptError::PromptTooLong { .. })
+            | ApiError::ContextLengthExceeded => StatusCode::BAD_REQUEST,
+            ApiError::Prompt(PromptError::CountTokens(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Prompt(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidOutput(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The number of seconds the client should wait before retrying the request, if the error is temporary.
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::QueueFull | ApiError::ServiceUnavailable(_) => Some(RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+
+    /// The error type, which is `rate_limit_error` if the request is rejected by the full queue, `invalid_request_error` for the other errors caused by the client, and `server_error` otherwise.
+    fn error_type(&self) -> &'static str {
+        match self {
+            ApiError::QueueFull => "rate_limit_error",
+            _ if self.status().is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        }
+    }
+
+    /// The request parameter related to the error, if any.
+    fn param(&self) -> Option<String> {
+        match self {
+            ApiError::InvalidParameter { param, .. } => Some(param.clone()),
+            ApiError::ModelNotFound(_) => Some(String::from("model")),
+            ApiError::Prompt(PromptError::UnsupportedTools | PromptError::InvalidTool(_)) => {
+                Some(String::from("tools"))
+            }
+            ApiError::Prompt(_) => Some(String::from("messages")),
+            _ => None,
+        }
+    }
+
+    /// A short machine-readable code of the error, if any.
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            ApiError::InvalidJson(_) => Some("invalid_json"),
+            ApiError::InvalidParameter { .. } => Some("invalid_value"),
+            ApiError::ModelNotFound(_) => Some("model_not_found"),
+            ApiError::EndpointNotFound(_) => Some("unknown_url"),
+            ApiError::CompletionNotFound(_) => Some("completion_not_found"),
+            ApiError::Cancelled => Some("cancelled"),
+            ApiError::Prompt(PromptError::PromptTooLong { .. })
+            | ApiError::ContextLengthExceeded => Some("context_length_exceeded"),
+            ApiError::Prompt(PromptError::CountTokens(_)) => None,
+            ApiError::Prompt(_) => Some("invalid_prompt"),
+            ApiError::QueueFull => Some("queue_full"),
+            ApiError::InvalidOutput(_) => Some("invalid_output"),
+            _ => None,
+        }
+    }
+
+    /// Serializes the error in the OpenAI error format.
+    pub(crate) fn to_json(&self) -> String {
+        let body = ErrorBody {
+            error: ErrorObject {
+                message: self.to_string(),
+                ty: self.error_type(),
+                param: self.param(),
+                code: self.code(),
+            },
+        };
+        serde_json::to_string(&body).unwrap_or_default()
+    }
+}
+impl From<ApiError> for Response<Body> {
+    fn from(err: ApiError) -> Self {
+        println!("[ERROR] {}", &err);
+
+        let mut response = Response::new(Body::from(err.to_json()));
+        *response.status_mut() = err.status();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::HeaderValue::from_static("*"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::HeaderValue::from_static("*"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::HeaderValue::from_static("*"),
+        );
+        if let Some(secs) = err.retry_after() {
+            headers.insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorObject,
+}
+
+#[derive(Serialize)]
+struct ErrorObject {
+    message: String,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    param: Option<String>,
+    code: Option<&'static str>,
+}
+
+pub(crate) fn error_response(err: ApiError) -> Result<Response<Body>, hyper::Error> {
+    Ok(err.into())
+}
+
+pub(crate) fn bad_request(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
+    error_response(ApiError::BadRequest(msg.as_ref().to_string()))
+}
+
+pub(crate) fn invalid_json(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
+    error_response(ApiError::InvalidJson(msg.as_ref().to_string()))
 }
 
 pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => format!("500 Internal Server Error"),
-        false => format!("500 Internal Server Error: {}", msg.as_ref()),
-    };
-    let mut response = Response::new(Body::from(err_msg));
-    *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
-    Ok(response)
+    error_response(ApiError::Internal(msg.as_ref().to_string()))
 }
 
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => format!("404 The requested service endpoint is not found"),
-        false => format!(
-            "404 The requested service endpoint is not found: {}",
-            msg.as_ref()
-        ),
-    };
-    let mut response = Response::new(Body::from(err_msg));
-    *response.status_mut() = hyper::StatusCode::NOT_FOUND;
-    Ok(response)
+    error_response(ApiError::EndpointNotFound(msg.as_ref().to_string()))
+}
+
+pub(crate) fn model_not_found(name: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
+    error_response(ApiError::ModelNotFound(name.as_ref().to_string()))
+}
+
+pub(crate) fn prompt_error(err: PromptError) -> Result<Response<Body>, hyper::Error> {
+    error_response(ApiError::Prompt(err))
 }
 
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
@@ -37,8 +201,98 @@ pub enum ServerError {
     SocketAddr(String),
     #[error("Internal server error: {0}")]
     InternalServerError(String),
+    #[error("Invalid command line argument: {0}")]
+    ArgumentError(String),
     #[error("Invalid prompt template type: {0}")]
     InvalidPromptTemplateType(String),
-    #[error("Failed to set `MAX_BUFFER_SIZE`. The `MAX_BUFFER_SIZE` is already set.")]
-    MaxBufferSize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(err: &ApiError) -> serde_json::Value {
+        serde_json::from_str(&err.to_json()).unwrap()
+    }
+
+    #[test]
+    fn invalid_parameter() {
+        let err = ApiError::InvalidParameter {
+            param: String::from("temperature"),
+            message: String::from("`temperature` must be between 0 and 2"),
+        };
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json(&err),
+            serde_json::json!({
+                "error": {
+                    "message": "`temperature` must be between 0 and 2",
+                    "type": "invalid_request_error",
+                    "param": "temperature",
+                    "code": "invalid_value",
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn model_not_found() {
+        let err = ApiError::ModelNotFound(String::from("gpt-4"));
+
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let json = json(&err);
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["param"], "model");
+        assert_eq!(json["error"]["code"], "model_not_found");
+    }
+
+    #[test]
+    fn prompt_error() {
+        let err = ApiError::from(PromptError::NoMessages);
+
+        assert_eq!(err.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let json = json(&err);
+        assert_eq!(json["error"]["param"], "messages");
+        assert_eq!(json["error"]["code"], "invalid_prompt");
+    }
+
+    #[test]
+    fn context_length_exceeded() {
+        let err = ApiError::ContextLengthExceeded;
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        let json = json(&err);
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["code"], "context_length_exceeded");
+    }
+
+    #[test]
+    fn internal_error() {
+        let err = ApiError::Internal(String::from("Fail to compute"));
+
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            json(&err),
+            serde_json::json!({
+                "error": {
+                    "message": "Internal server error: Fail to compute",
+                    "type": "server_error",
+                    "param": null,
+                    "code": null,
+                }
+            })
+        );
+    }
+    #[test]
+    fn queue_full() {
+        let err = ApiError::QueueFull;
+        let json = json(&err);
+        assert_eq!(json["error"]["type"], "rate_limit_error");
+        assert_eq!(json["error"]["code"], "queue_full");
+
+        let response = Response::from(err);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
 }
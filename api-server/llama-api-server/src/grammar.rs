@@ -0,0 +1,303 @@
+//! Constrains the output of the models with the GBNF grammars of llama.cpp, so that a chat completion with a `response_format` is always valid JSON, and validates the output against the requested format.
+
+use endpoints::chat::{JSONSchemaDefine, JSONSchemaType, ResponseFormat};
+use serde_json::Value;
+
+/// The rules of any JSON value.
+const JSON_RULES: &str = r#"value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array ::= "[" ws ( value ( "," ws value )* )? "]" ws
+string ::= "\"" ( [^"\\] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\"" ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws
+integer ::= "-"? ( [0-9] | [1-9] [0-9]* ) ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws
+ws ::= ( [ \t\n] ws )?"#;
+
+/// Returns the grammar constraining the output to the response format, if the format is not plain text.
+pub(crate) fn grammar(response_format: &ResponseFormat) -> Option<String> {
+    match response_format {
+        ResponseFormat::Text => None,
+        ResponseFormat::JsonObject => Some(format!("root ::= object\n{JSON_RULES}")),
+        ResponseFormat::JsonSchema { json_schema } => {
+            let mut rules = vec![];
+            let root = schema_rule(&json_schema.schema, &mut rules);
+            rules.insert(0, format!("root ::= {root}"));
+            rules.push(JSON_RULES.to_owned());
+            Some(rules.join("\n"))
+        }
+    }
+}
+
+/// Writes the rules of the schema, and returns the expression matching it.
+fn schema_rule(schema: &JSONSchemaDefine, rules: &mut Vec<String>) -> String {
+    if let Some(values) = &schema.enum_values {
+        let alternatives: Vec<String> = values
+            .iter()
+            .map(|value| {
+                format!(
+                    "{literal} ws",
+                    literal = literal(&Value::from(value.as_str()))
+                )
+            })
+            .collect();
+        return add_rule(format!("( {} )", alternatives.join(" | ")), rules);
+    }
+
+    match schema.schema_type {
+        Some(JSONSchemaType::Object) => match &schema.properties {
+            Some(properties) if !properties.is_empty() => {
+                // the required properties come first in the given order, followed by the optional ones in the alphabetical order
+                let required = schema.required.clone().unwrap_or_default();
+                let mut optional: Vec<&String> = properties
+                    .keys()
+                    .filter(|name| !required.contains(name))
+                    .collect();
+                optional.sort();
+
+                let mut members = vec![];
+                for name in required.iter() {
+                    let value = match properties.get(name) {
+                        Some(property) => schema_rule(property, rules),
+                        None => String::from("value"),
+                    };
+                    members.push(member(name, &value));
+                }
+                let optional: Vec<String> = optional
+                    .into_iter()
+                    .map(|name| member(name, &schema_rule(&properties[name], rules)))
+                    .collect();
+                // a comma only follows a member which is written
+                let tail = |members: &[String]| -> String {
+                    members
+                        .iter()
+                        .map(|member| format!(" ( \",\" ws {member} )?"))
+                        .collect()
+                };
+
+                let expr = match members.is_empty() {
+                    true => {
+                        // the first optional member written comes first, followed by the later ones
+                        let alternatives: Vec<String> = (0..optional.len())
+                            .map(|i| format!("{}{}", optional[i], tail(&optional[i + 1..])))
+                            .collect();
+                        format!("( {} )?", alternatives.join(" | "))
+                    }
+                    false => format!("{}{}", members.join(" \",\" ws "), tail(&optional)),
+                };
+                add_rule(format!("\"{{\" ws {expr} \"}}\" ws"), rules)
+            }
+            _ => String::from("object"),
+        },
+        Some(JSONSchemaType::Array) => match &schema.items {
+            Some(items) => {
+                let item = schema_rule(items, rules);
+                add_rule(
+                    format!("\"[\" ws ( {item} ( \",\" ws {item} )* )? \"]\" ws"),
+                    rules,
+                )
+            }
+            None => String::from("array"),
+        },
+        Some(JSONSchemaType::String) => String::from("string"),
+        Some(JSONSchemaType::Number) => String::from("number"),
+        Some(JSONSchemaType::Integer) => String::from("integer"),
+        Some(JSONSchemaType::Boolean) => String::from("boolean"),
+        Some(JSONSchemaType::Null) => String::from("null"),
+        None => String::from("value"),
+    }
+}
+
+/// Adds a rule with the expression, and returns the name of the rule.
+fn add_rule(expr: String, rules: &mut Vec<String>) -> String {
+    let name = format!("rule-{}", rules.len() + 1);
+    rules.push(format!("{name} ::= {expr}"));
+    name
+}
+
+/// The expression of an object member with the given name and value.
+fn member(name: &str, value: &str) -> String {
+    format!(
+        "{key} ws \":\" ws {value}",
+        key = literal(&Value::from(name))
+    )
+}
+
+/// Writes the JSON value as a string literal of the grammar.
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    format!(
+        "\"{}\"",
+        json.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Checks if the output is valid in the response format. Returns the reason if not.
+pub(crate) fn validate(output: &str, response_format: &ResponseFormat) -> Result<(), String> {
+    let value = match response_format {
+        ResponseFormat::Text => return Ok(()),
+        _ => serde_json::from_str::<Value>(output)
+            .map_err(|e| format!("The output is not valid JSON: {msg}", msg = e))?,
+    };
+
+    match response_format {
+        ResponseFormat::JsonSchema { json_schema } => {
+            validate_value(&value, &json_schema.schema, "$")
+        }
+        _ if !value.is_object() => Err(String::from("The output is not a JSON object")),
+        _ => Ok(()),
+    }
+}
+
+/// Checks if the value matches the schema. `path` locates the value in the output.
+fn validate_value(value: &Value, schema: &JSONSchemaDefine, path: &str) -> Result<(), String> {
+    if let Some(values) = &schema.enum_values {
+        if !values.iter().any(|allowed| value.as_str() == Some(allowed)) {
+            return Err(format!(
+                "`{path}` must be one of {values:?}",
+                path = path,
+                values = values
+            ));
+        }
+    }
+
+    let matched = match schema.schema_type {
+        Some(JSONSchemaType::Object) => value.is_object(),
+        Some(JSONSchemaType::Array) => value.is_array(),
+        Some(JSONSchemaType::String) => value.is_string(),
+        Some(JSONSchemaType::Number) => value.is_number(),
+        Some(JSONSchemaType::Integer) => value.is_i64() || value.is_u64(),
+        Some(JSONSchemaType::Boolean) => value.is_boolean(),
+        Some(JSONSchemaType::Null) => value.is_null(),
+        None => true,
+    };
+    if !matched {
+        return Err(format!(
+            "`{path}` must be of type {ty}",
+            path = path,
+            ty = serde_json::to_string(&schema.schema_type).unwrap_or_default()
+        ));
+    }
+
+    if let Value::Object(object) = value {
+        for name in schema.required.iter().flatten() {
+            if !object.contains_key(name) {
+                return Err(format!("`{path}.{name}` is required"));
+            }
+        }
+        if let Some(properties) = &schema.properties {
+            for (name, property) in properties {
+                if let Some(member) = object.get(name) {
+                    validate_value(member, property, &format!("{path}.{name}"))?;
+                }
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(schema)) = (value, &schema.items) {
+        for (i, item) in items.iter().enumerate() {
+            validate_value(item, schema, &format!("{path}[{i}]"))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_schema(schema: Value) -> ResponseFormat {
+        serde_json::from_value(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {"name": "answer", "schema": schema},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn object_with_required_and_optional_properties() {
+        let format = json_schema(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "city": {"type": "string"},
+            },
+            "required": ["name"],
+        }));
+
+        let grammar = grammar(&format).unwrap();
+        assert!(grammar.starts_with(
+            "root ::= rule-1\nrule-1 ::= \"{\" ws \"\\\"name\\\"\" ws \":\" ws string ( \",\" ws \"\\\"age\\\"\" ws \":\" ws integer )? ( \",\" ws \"\\\"city\\\"\" ws \":\" ws string )? \"}\" ws\n"
+        ));
+    }
+
+    #[test]
+    fn object_with_optional_properties_only() {
+        let format = json_schema(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "a": {"type": "string"},
+                "b": {"type": "number"},
+                "c": {"type": "boolean"},
+            },
+        }));
+        let a = "\"\\\"a\\\"\" ws \":\" ws string";
+        let b = "\"\\\"b\\\"\" ws \":\" ws number";
+        let c = "\"\\\"c\\\"\" ws \":\" ws boolean";
+
+        // none of the alternatives begins with a comma
+        let grammar = grammar(&format).unwrap();
+        assert!(grammar.starts_with(&format!(
+            "root ::= rule-1\nrule-1 ::= \"{{\" ws ( {a} ( \",\" ws {b} )? ( \",\" ws {c} )? | {b} ( \",\" ws {c} )? | {c} )? \"}}\" ws\n"
+        )));
+    }
+
+    #[test]
+    fn array_of_enum() {
+        let format = json_schema(serde_json::json!({
+            "type": "array",
+            "items": {"type": "string", "enum": ["red", "green"]},
+        }));
+
+        let grammar = grammar(&format).unwrap();
+        assert!(grammar.starts_with(
+            "root ::= rule-2\nrule-1 ::= ( \"\\\"red\\\"\" ws | \"\\\"green\\\"\" ws )\nrule-2 ::= \"[\" ws ( rule-1 ( \",\" ws rule-1 )* )? \"]\" ws\n"
+        ));
+    }
+
+    #[test]
+    fn validate_output() {
+        let format = json_schema(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"type": "string", "enum": ["a", "b"]}},
+            },
+            "required": ["name"],
+        }));
+
+        assert!(validate(r#"{"name": "x", "tags": ["a", "b"]}"#, &format).is_ok());
+        assert!(validate(r#"{"name": "x"}"#, &format).is_ok());
+        assert_eq!(
+            validate(r#"{"tags": []}"#, &format),
+            Err(String::from("`$.name` is required"))
+        );
+        assert_eq!(
+            validate(r#"{"name": 1}"#, &format),
+            Err(String::from("`$.name` must be of type \"string\""))
+        );
+        assert_eq!(
+            validate(r#"{"name": "x", "tags": ["c"]}"#, &format),
+            Err(String::from("`$.tags[0]` must be one of [\"a\", \"b\"]"))
+        );
+        assert!(validate(r#"{"name": "x""#, &format).is_err());
+
+        assert!(validate("[]", &ResponseFormat::JsonObject).is_err());
+        assert!(validate("{}", &ResponseFormat::JsonObject).is_ok());
+        assert!(validate("not json", &ResponseFormat::Text).is_ok());
+    }
+}
@@ -1,7 +1,15 @@
 mod backend;
+mod cancel;
 mod error;
-
-use chat_prompts::PromptTemplateType;
+mod grammar;
+mod scheduler;
+
+use chat_prompts::{
+    chat::{custom::CustomPrompt, jinja::JinjaPrompt, ChatPrompt},
+    gguf::{self, GgufMetadata},
+    output::OutputError,
+    read_wasi_nn_output, PromptTemplateType,
+};
 use clap::{crate_version, Arg, ArgAction, Command};
 use error::ServerError;
 use hyper::{
@@ -10,16 +18,23 @@ use hyper::{
     Body, Request, Response, Server, StatusCode,
 };
 use once_cell::sync::OnceCell;
+use scheduler::Scheduler;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Mutex};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
 use wasi_nn::{Error as WasiNnError, Graph as WasiNnGraph, GraphExecutionContext, TensorType};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 const DEFAULT_SOCKET_ADDRESS: &str = "0.0.0.0:8080";
 
-static MAX_BUFFER_SIZE: OnceCell<usize> = OnceCell::new();
-static GRAPH: OnceCell<Mutex<Graph>> = OnceCell::new();
+static SCHEDULERS: OnceCell<HashMap<String, Scheduler>> = OnceCell::new();
+static SYSTEM_FINGERPRINT: OnceCell<String> = OnceCell::new();
 
 #[derive(Clone, Debug)]
 pub struct AppState {
@@ -43,7 +58,9 @@ async fn main() -> Result<(), ServerError> {
                 .short('m')
                 .long("model-name")
                 .value_name("MODEL-NAME")
-                .help("Sets the model name")
+                .help("Sets the model names, separated by comma. Each name is paired with the model alias at the same position")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .default_value("default"),
         )
         .arg(
@@ -51,7 +68,9 @@ async fn main() -> Result<(), ServerError> {
                 .short('a')
                 .long("model-alias")
                 .value_name("MODEL-ALIAS")
-                .help("Sets the alias name of the model in WasmEdge runtime")
+                .help("Sets the alias names of the models in WasmEdge runtime, separated by comma")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .default_value("default"),
         )
         .arg(
@@ -106,12 +125,21 @@ async fn main() -> Result<(), ServerError> {
                 .help("Penalize repeat sequence of tokens")
                 .default_value("1.1"),
         )
+        .arg(
+            Arg::new("top_p")
+                .long("top-p")
+                .value_parser(clap::value_parser!(f32))
+                .value_name("TOP_P")
+                .help("Top-p sampling, limit the next token selection to a subset of tokens with a cumulative probability above the threshold")
+                .default_value("1.0"),
+        )
         .arg(
             Arg::new("reverse_prompt")
                 .short('r')
                 .long("reverse-prompt")
                 .value_name("REVERSE_PROMPT")
-                .help("Halt generation at PROMPT, return control."),
+                .action(ArgAction::Append)
+                .help("Halt generation at PROMPT, return control. Repeat it to halt at any of several prompts."),
         )
         .arg(
             Arg::new("prompt_template")
@@ -134,17 +162,54 @@ async fn main() -> Result<(), ServerError> {
                     "intel-neural",
                     "deepseek-chat",
                     "deepseek-coder",
+                    "jinja",
+                    "auto",
                 ])
                 .value_name("TEMPLATE")
-                .help("Sets the prompt template.")
-                .default_value("llama-2-chat"),
+                .help("Sets the prompt templates, separated by comma. A single template applies to all models. `auto` detects the templates from the model files, or uses `llama-2-chat` if no model file is given")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .default_value("auto"),
         )
         .arg(
-            Arg::new("stream")
-                .long("stream")
-                .value_name("STREAM")
-                .help("Enable streaming mode")
-                .action(ArgAction::SetTrue),
+            Arg::new("parallel")
+                .long("parallel")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .value_name("PARALLEL")
+                .help("Number of execution contexts of each model, i.e. the number of requests served by a model at the same time")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("queue_size")
+                .long("queue-size")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .value_name("QUEUE_SIZE")
+                .help("Maximum number of requests waiting for each model. The requests beyond the limit are rejected with 429 Too Many Requests")
+                .default_value("16"),
+        )
+        .arg(
+            Arg::new("prompt_template_file")
+                .long("prompt-template-file")
+                .value_name("FILE")
+                .help("Sets the TOML files of user-defined prompt templates, separated by comma. A single file applies to all models. Overrides `--prompt-template`")
+                .action(ArgAction::Append)
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("model_file")
+                .long("model-file")
+                .value_name("FILE")
+                .help("Sets the GGUF model files, separated by comma, in the order of the model aliases. Used to detect the prompt templates, and required by the `jinja` prompt template if no chat template file is given")
+                .action(ArgAction::Append)
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("chat_template_file")
+                .long("chat-template-file")
+                .value_name("FILE")
+                .help("Sets the Jinja chat template files of the `jinja` prompt template, separated by comma. A single file applies to all models. Defaults to the chat templates in the model files")
+                .action(ArgAction::Append)
+                .value_delimiter(','),
         )
         .arg(
             Arg::new("log_prompts")
@@ -192,19 +257,150 @@ async fn main() -> Result<(), ServerError> {
         socket_addr = socket_addr
     );
 
-    // model name
-    let model_name = matches.get_one::<String>("model_name").unwrap().to_string();
-    println!("[INFO] Model name: {name}", name = &model_name);
+    // model aliases
+    let model_aliases: Vec<String> = matches
+        .get_many::<String>("model_alias")
+        .unwrap()
+        .map(|alias| alias.to_string())
+        .collect();
 
-    // model alias
-    let model_alias = matches
-        .get_one::<String>("model_alias")
+    // model names
+    let mut model_names: Vec<String> = matches
+        .get_many::<String>("model_name")
         .unwrap()
-        .to_string();
-    println!("[INFO] Model alias: {alias}", alias = &model_alias);
+        .map(|name| name.to_string())
+        .collect();
+    if model_names.len() != model_aliases.len() {
+        match matches.value_source("model_name") {
+            Some(clap::parser::ValueSource::DefaultValue) => {
+                // name the models after their aliases
+                model_names = model_aliases.clone();
+            }
+            _ => {
+                return Err(ServerError::ArgumentError(format!(
+                    "The number of model names ({names}) does not match the number of model aliases ({aliases})",
+                    names = model_names.len(),
+                    aliases = model_aliases.len()
+                )));
+            }
+        }
+    }
 
-    // create a `ModelInfo` instance
-    let model_info = ModelInfo::new(model_name);
+    // model files
+    let model_files: Vec<String> = matches
+        .get_many::<String>("model_file")
+        .map(|files| files.map(|file| file.to_string()).collect())
+        .unwrap_or_default();
+    if !model_files.is_empty() && model_files.len() != model_aliases.len() {
+        return Err(ServerError::ArgumentError(format!(
+            "The number of model files ({files}) does not match the number of model aliases ({aliases})",
+            files = model_files.len(),
+            aliases = model_aliases.len()
+        )));
+    }
+
+    // chat template files
+    let mut chat_template_files: Vec<String> = matches
+        .get_many::<String>("chat_template_file")
+        .map(|files| files.map(|file| file.to_string()).collect())
+        .unwrap_or_default();
+    if chat_template_files.len() == 1 {
+        chat_template_files = vec![chat_template_files[0].clone(); model_aliases.len()];
+    } else if !chat_template_files.is_empty() && chat_template_files.len() != model_aliases.len() {
+        return Err(ServerError::ArgumentError(format!(
+            "The number of chat template files ({files}) does not match the number of model aliases ({aliases})",
+            files = chat_template_files.len(),
+            aliases = model_aliases.len()
+        )));
+    }
+
+    // prompt templates
+    let mut templates = vec![];
+    match matches.get_many::<String>("prompt_template_file") {
+        Some(files) => {
+            for file in files {
+                match CustomPrompt::from_file(file) {
+                    Ok(template) => templates.push((
+                        PromptTemplateType::Custom,
+                        ChatPrompt::CustomPrompt(template),
+                    )),
+                    Err(e) => {
+                        return Err(ServerError::InvalidPromptTemplateType(e.to_string()));
+                    }
+                }
+            }
+        }
+        None => {
+            let mut prompt_templates: Vec<&String> = matches
+                .get_many::<String>("prompt_template")
+                .unwrap()
+                .collect();
+            if prompt_templates.len() == 1 {
+                prompt_templates = vec![prompt_templates[0]; model_aliases.len()];
+            }
+
+            for (i, prompt_template) in prompt_templates.into_iter().enumerate() {
+                let metadata = match model_files.get(i) {
+                    Some(model_file) => Some(
+                        GgufMetadata::from_file(model_file)
+                            .map_err(|e| ServerError::InvalidPromptTemplateType(e.to_string()))?,
+                    ),
+                    None => None,
+                };
+                let (template_ty, warning) =
+                    gguf::resolve_prompt_template(prompt_template, metadata.as_ref())
+                        .map_err(|e| ServerError::InvalidPromptTemplateType(e.to_string()))?;
+                if let Some(warning) = warning {
+                    println!("[WARNING] {warning}");
+                } else if *prompt_template == "auto" {
+                    println!("[INFO] Detected prompt template: {ty}", ty = template_ty);
+                }
+                let template = match template_ty {
+                    PromptTemplateType::Jinja => {
+                        JinjaPrompt::load(chat_template_files.get(i), model_files.get(i))
+                            .map(ChatPrompt::JinjaPrompt)
+                    }
+                    _ => ChatPrompt::try_from(template_ty),
+                };
+                match template {
+                    Ok(template) => templates.push((template_ty, template)),
+                    Err(e) => {
+                        return Err(ServerError::InvalidPromptTemplateType(e.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    if templates.len() == 1 {
+        templates = vec![templates[0].clone(); model_aliases.len()];
+    } else if templates.len() != model_aliases.len() {
+        return Err(ServerError::ArgumentError(format!(
+            "The number of prompt templates ({templates}) does not match the number of model aliases ({aliases})",
+            templates = templates.len(),
+            aliases = model_aliases.len()
+        )));
+    }
+
+    // create `ModelInfo` instances
+    let mut models = vec![];
+    for ((name, alias), (template_ty, template)) in model_names
+        .iter()
+        .zip(model_aliases.iter())
+        .zip(templates.into_iter())
+    {
+        if models.iter().any(|model: &ModelInfo| &model.name == name) {
+            return Err(ServerError::ArgumentError(format!(
+                "Duplicate model name: {name}"
+            )));
+        }
+        println!(
+            "[INFO] Model name: {name}, alias: {alias}, prompt template: {ty:?}",
+            name = name,
+            alias = alias,
+            ty = template_ty
+        );
+        models.push(ModelInfo::new(name, alias, template_ty, template));
+    }
 
     // create an `Options` instance
     let mut options = Metadata::default();
@@ -214,11 +410,6 @@ async fn main() -> Result<(), ServerError> {
     println!("[INFO] Prompt context size: {size}", size = ctx_size);
     options.ctx_size = *ctx_size as u64;
 
-    // max buffer size
-    if MAX_BUFFER_SIZE.set(*ctx_size as usize * 6).is_err() {
-        return Err(ServerError::MaxBufferSize);
-    }
-
     // number of tokens to predict
     let n_predict = matches.get_one::<u32>("n_predict").unwrap();
     println!("[INFO] Number of tokens to predict: {n}", n = n_predict);
@@ -253,30 +444,35 @@ async fn main() -> Result<(), ServerError> {
     );
     options.repeat_penalty = *repeat_penalty;
 
-    // reverse_prompt
-    if let Some(reverse_prompt) = matches.get_one::<String>("reverse_prompt") {
-        println!("[INFO] Reverse prompt: {prompt}", prompt = &reverse_prompt);
-        options.reverse_prompt = Some(reverse_prompt.to_string());
+    // top-p
+    let top_p = matches.get_one::<f32>("top_p").unwrap();
+    println!("[INFO] Top-p sampling: {top_p}", top_p = top_p);
+    options.top_p = *top_p;
+
+    // reverse_prompt, of which the backend only knows the first one, while the server stops at any of them
+    let reverse_prompts: Vec<String> = matches
+        .get_many::<String>("reverse_prompt")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    for reverse_prompt in reverse_prompts.iter() {
+        println!("[INFO] Reverse prompt: {prompt}", prompt = reverse_prompt);
     }
+    options.reverse_prompt = reverse_prompts.first().cloned();
 
-    // type of prompt template
-    let prompt_template = matches
-        .get_one::<String>("prompt_template")
-        .unwrap()
-        .to_string();
-    let template_ty = match PromptTemplateType::from_str(&prompt_template) {
-        Ok(template) => template,
-        Err(e) => {
-            return Err(ServerError::InvalidPromptTemplateType(e.to_string()));
-        }
-    };
-    println!("[INFO] Prompt template: {ty:?}", ty = &template_ty);
-    let ref_template_ty = std::sync::Arc::new(template_ty);
+    // number of execution contexts
+    let parallel = matches.get_one::<u32>("parallel").unwrap();
+    println!(
+        "[INFO] Number of execution contexts of each model: {n}",
+        n = parallel
+    );
 
-    // streaming mode
-    // let disable_stream = matches.get_flag("disable_stream");
-    let stream = matches.get_flag("stream");
-    println!("[INFO] Enable streaming mode: {enable}", enable = stream);
+    // queue size
+    let queue_size = matches.get_one::<u32>("queue_size").unwrap();
+    println!(
+        "[INFO] Maximum number of waiting requests of each model: {n}",
+        n = queue_size
+    );
 
     // log prompts
     let log_prompts = matches.get_flag("log_prompts");
@@ -298,10 +494,45 @@ async fn main() -> Result<(), ServerError> {
 
     println!("[INFO] Starting server ...");
 
-    let graph = Graph::new(model_alias, &options);
-    if GRAPH.set(Mutex::new(graph)).is_err() {
+    // the fingerprint of the backend configuration
+    let mut hasher = DefaultHasher::new();
+    crate_version!().hash(&mut hasher);
+    if let Ok(config) = serde_json::to_string(&options) {
+        config.hash(&mut hasher);
+    }
+    if SYSTEM_FINGERPRINT
+        .set(format!("fp_{:010x}", hasher.finish() & 0xff_ffff_ffff))
+        .is_err()
+    {
         return Err(ServerError::InternalServerError(
-            "The GRAPH has already been initialized".to_owned(),
+            "The SYSTEM_FINGERPRINT has already been initialized".to_owned(),
+        ));
+    }
+
+    let mut schedulers = HashMap::new();
+    for model in models.iter() {
+        let graph = Graph::new(&model.alias, &options);
+        let mut graphs = Vec::with_capacity(*parallel as usize);
+        for _ in 1..*parallel {
+            match graph.fork() {
+                Ok(graph) => graphs.push(graph),
+                Err(e) => {
+                    return Err(ServerError::InternalServerError(format!(
+                        "Fail to init execution context: {msg}",
+                        msg = e
+                    )));
+                }
+            }
+        }
+        graphs.push(graph);
+        schedulers.insert(
+            model.name.clone(),
+            Scheduler::new(graphs, *queue_size as usize),
+        );
+    }
+    if SCHEDULERS.set(schedulers).is_err() {
+        return Err(ServerError::InternalServerError(
+            "The SCHEDULERS has already been initialized".to_owned(),
         ));
     }
 
@@ -312,35 +543,27 @@ async fn main() -> Result<(), ServerError> {
         .as_secs();
     let ref_created = std::sync::Arc::new(created);
 
-    // stop the generation at the prompt
-    let ref_stop = std::sync::Arc::new(options.reverse_prompt);
+    // stop the generation at the reverse prompts
+    let ref_stops = std::sync::Arc::new(reverse_prompts);
 
     let new_service = make_service_fn(move |_| {
-        let model_info = model_info.clone();
-        let prompt_template_ty = ref_template_ty.clone();
+        let models = models.clone();
         let created = ref_created.clone();
         let log_prompts = ref_log_prompts.clone();
         let web_ui = matches
             .get_one::<String>("web_ui")
             .unwrap_or(&"chatbot-ui".to_owned())
             .to_string();
-        let stop = std::sync::Arc::clone(&ref_stop);
+        let stops = std::sync::Arc::clone(&ref_stops);
         async move {
             Ok::<_, Error>(service_fn(move |req| {
-                let stop = match stop.as_ref() {
-                    Some(prompt) => Some(prompt.to_string()),
-                    None => None,
-                };
-
                 handle_request(
                     req,
-                    model_info.clone(),
-                    *prompt_template_ty.clone(),
+                    models.clone(),
                     *created.clone(),
                     *log_prompts.clone(),
                     web_ui.clone(),
-                    stream,
-                    stop,
+                    stops.to_vec(),
                 )
             }))
         }
@@ -358,13 +581,11 @@ async fn main() -> Result<(), ServerError> {
 
 async fn handle_request(
     req: Request<Body>,
-    model_info: ModelInfo,
-    template_ty: PromptTemplateType,
+    models: Vec<ModelInfo>,
     created: u64,
     log_prompts: bool,
     web_ui: String,
-    stream: bool,
-    stop: Option<String>,
+    stops: Vec<String>,
 ) -> Result<Response<Body>, hyper::Error> {
     let path_str = req.uri().path();
     let path_buf = PathBuf::from(path_str);
@@ -377,18 +598,7 @@ async fn handle_request(
         "/echo" => {
             return Ok(Response::new(Body::from("echo test")));
         }
-        "/v1" => {
-            backend::handle_llama_request(
-                req,
-                model_info,
-                template_ty,
-                created,
-                log_prompts,
-                stream,
-                stop,
-            )
-            .await
-        }
+        "/v1" => backend::handle_llama_request(req, models, created, log_prompts, stops).await,
         _ => Ok(static_response(path_str, web_ui)),
     }
 }
@@ -436,26 +646,54 @@ struct Metadata {
     temp: f32,
     #[serde(rename = "repeat-penalty")]
     repeat_penalty: f32,
+    #[serde(rename = "top-p")]
+    top_p: f32,
     #[serde(skip_serializing_if = "Option::is_none", rename = "reverse-prompt")]
     reverse_prompt: Option<String>,
+    #[serde(rename = "embedding")]
+    embedding: bool,
+    /// The GBNF grammar constraining the output.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "grammar")]
+    grammar: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub(crate) struct ModelInfo {
     name: String,
+    alias: String,
+    template_ty: PromptTemplateType,
+    template: ChatPrompt,
 }
 impl ModelInfo {
-    fn new(name: impl AsRef<str>) -> Self {
+    fn new(
+        name: impl AsRef<str>,
+        alias: impl AsRef<str>,
+        template_ty: PromptTemplateType,
+        template: ChatPrompt,
+    ) -> Self {
         Self {
             name: name.as_ref().to_string(),
+            alias: alias.as_ref().to_string(),
+            template_ty,
+            template,
         }
     }
+
+    /// The identifier of the model listed by the `/v1/models` endpoint.
+    pub(crate) fn id(&self) -> String {
+        format!(
+            "{name}:{template}",
+            name = self.name,
+            template = self.template_ty
+        )
+    }
 }
 
 #[derive(Debug)]
 struct Graph {
-    _graph: WasiNnGraph,
+    _graph: Arc<WasiNnGraph>,
     context: GraphExecutionContext,
+    metadata: Metadata,
 }
 impl Graph {
     pub fn new(model_alias: impl AsRef<str>, options: &Metadata) -> Self {
@@ -474,11 +712,41 @@ impl Graph {
         let context = graph.init_execution_context().unwrap();
 
         Self {
-            _graph: graph,
+            _graph: Arc::new(graph),
             context,
+            metadata: options.clone(),
         }
     }
 
+    /// Creates another execution context of the loaded model, so that the model can serve more than one request at the same time.
+    pub fn fork(&self) -> Result<Self, WasiNnError> {
+        let context = self._graph.init_execution_context()?;
+
+        Ok(Self {
+            _graph: self._graph.clone(),
+            context,
+            metadata: self.metadata.clone(),
+        })
+    }
+
+    /// Sets the metadata used by the subsequent computations.
+    pub fn update_metadata(&mut self, metadata: &Metadata) -> Result<(), String> {
+        let config = match serde_json::to_string(metadata) {
+            Ok(config) => config,
+            Err(e) => return Err(format!("Fail to serialize metadata: {msg}", msg = e)),
+        };
+
+        self.context
+            .set_input(1, TensorType::U8, &[1], config.as_bytes())
+            .map_err(|e| format!("Fail to set metadata: {msg}", msg = e))
+    }
+
+    /// Restores the metadata the graph was loaded with.
+    pub fn reset_metadata(&mut self) -> Result<(), String> {
+        let metadata = self.metadata.clone();
+        self.update_metadata(&metadata)
+    }
+
     pub fn set_input<T: Sized>(
         &mut self,
         index: usize,
@@ -497,19 +765,18 @@ impl Graph {
         self.context.compute_single()
     }
 
-    pub fn get_output<T: Sized>(
-        &self,
-        index: usize,
-        out_buffer: &mut [T],
-    ) -> Result<usize, WasiNnError> {
-        self.context.get_output(index, out_buffer)
+    /// Ends the computation token by token, and resets the execution context for the next one.
+    pub fn finish_single(&mut self) -> Result<(), WasiNnError> {
+        self.context.fini_single()
     }
 
-    pub fn get_output_single<T: Sized>(
-        &self,
-        index: usize,
-        out_buffer: &mut [T],
-    ) -> Result<usize, WasiNnError> {
-        self.context.get_output_single(index, out_buffer)
+    /// Retrieves the whole output of the computation at the given index.
+    pub fn get_output(&self, index: usize) -> Result<Vec<u8>, OutputError> {
+        read_wasi_nn_output!(|buffer| self.context.get_output(index, buffer))
+    }
+
+    /// Retrieves the output of the last token computed by `compute_single` at the given index.
+    pub fn get_output_single(&self, index: usize) -> Result<Vec<u8>, OutputError> {
+        read_wasi_nn_output!(|buffer| self.context.get_output_single(index, buffer))
     }
 }
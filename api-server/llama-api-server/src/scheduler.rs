@@ -0,0 +1,85 @@
+//! The inference scheduler of a model. Each execution context of the model is owned by a worker task, which takes the jobs from a bounded queue shared by all the workers and runs them one by one. So a job always has an execution context of its own until it finishes, no matter how many requests arrive at the same time.
+
+use crate::{error::ApiError, Graph};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot, Mutex,
+};
+
+/// A job run by a worker. The job takes over the execution context of the worker, and gives it back when it finishes.
+pub(crate) type Job = Box<dyn FnOnce(Graph) -> BoxFuture<'static, Graph> + Send>;
+
+pub(crate) struct Scheduler {
+    sender: mpsc::Sender<Job>,
+}
+impl Scheduler {
+    /// Creates a scheduler which spawns a worker for each of the given execution contexts.
+    ///
+    /// # Arguments
+    ///
+    /// * `graphs` - The execution contexts of the model.
+    ///
+    /// * `queue_size` - The maximum number of jobs waiting for an execution context. Must be greater than 0.
+    pub(crate) fn new(graphs: Vec<Graph>, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for graph in graphs {
+            tokio::spawn(work(graph, receiver.clone()));
+        }
+
+        Self { sender }
+    }
+
+    /// Puts the job into the queue. Fails without waiting if the queue is full.
+    pub(crate) fn submit(&self, job: Job) -> Result<(), ApiError> {
+        self.sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => ApiError::QueueFull,
+            TrySendError::Closed(_) => {
+                ApiError::ServiceUnavailable(String::from("No execution context is available"))
+            }
+        })
+    }
+
+    /// Runs the given function with an execution context, and returns its result.
+    pub(crate) async fn run<T, F>(&self, f: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut Graph) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_async(move |graph| Box::pin(async move { f(graph) }))
+            .await
+    }
+
+    /// Runs the given asynchronous function with an execution context, and returns its result. A long computation yields between its steps, e.g. the tokens of an answer, so that the other requests are served meanwhile.
+    pub(crate) async fn run_async<T, F>(&self, f: F) -> Result<T, ApiError>
+    where
+        F: for<'a> FnOnce(&'a mut Graph) -> BoxFuture<'a, T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.submit(Box::new(move |mut graph| {
+            Box::pin(async move {
+                // the receiver is gone if the request has been dropped
+                let _ = sender.send(f(&mut graph).await);
+                graph
+            })
+        }))?;
+
+        receiver.await.map_err(|_| {
+            ApiError::ServiceUnavailable(String::from("The job is dropped by the worker"))
+        })
+    }
+}
+
+/// Runs the jobs in the queue with the given execution context until the scheduler is dropped.
+async fn work(mut graph: Graph, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    loop {
+        let job = receiver.lock().await.recv().await;
+        match job {
+            Some(job) => graph = job(graph).await,
+            None => break,
+        }
+    }
+}
@@ -1,15 +1,17 @@
 use chat_prompts::{
-    chat::{BuildChatPrompt, ChatPrompt},
+    budget::fit_prompt,
+    chat::{custom::CustomPrompt, jinja::JinjaPrompt, BuildChatPrompt, ChatPrompt},
+    error::PromptError,
+    gguf::{self, GgufMetadata},
+    read_wasi_nn_output,
+    stop::StopMatcher,
+    utf8::Utf8Decoder,
     PromptTemplateType,
 };
 use clap::{crate_version, Arg, ArgAction, Command};
 use endpoints::chat::{ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionRole};
-use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
-use std::str::FromStr;
-
-static MAX_BUFFER_SIZE: OnceCell<usize> = OnceCell::new();
 
 #[allow(unreachable_code)]
 fn main() -> Result<(), String> {
@@ -80,7 +82,8 @@ fn main() -> Result<(), String> {
                 .short('r')
                 .long("reverse-prompt")
                 .value_name("REVERSE_PROMPT")
-                .help("Halt generation at PROMPT, return control."),
+                .action(ArgAction::Append)
+                .help("Halt generation at PROMPT, return control. Repeat it to halt at any of several prompts."),
         )
         .arg(
             Arg::new("system_prompt")
@@ -111,10 +114,30 @@ fn main() -> Result<(), String> {
                     "intel-neural",
                     "deepseek-chat",
                     "deepseek-coder",
+                    "jinja",
+                    "auto",
                 ])
                 .value_name("TEMPLATE")
-                .help("Prompt template.")
-                .default_value("llama-2-chat"),
+                .help("Prompt template. `auto` detects the template from the model file, or uses `llama-2-chat` if no model file is given")
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::new("prompt_template_file")
+                .long("prompt-template-file")
+                .value_name("FILE")
+                .help("TOML file of a user-defined prompt template. Overrides `--prompt-template`"),
+        )
+        .arg(
+            Arg::new("model_file")
+                .long("model-file")
+                .value_name("FILE")
+                .help("GGUF model file. Used to detect the prompt template, and required by the `jinja` prompt template if no chat template file is given"),
+        )
+        .arg(
+            Arg::new("chat_template_file")
+                .long("chat-template-file")
+                .value_name("FILE")
+                .help("Jinja chat template file of the `jinja` prompt template. Defaults to the chat template in the model file"),
         )
         .arg(
             Arg::new("log_prompts")
@@ -155,13 +178,6 @@ fn main() -> Result<(), String> {
     println!("[INFO] Prompt context size: {size}", size = ctx_size);
     options.ctx_size = *ctx_size;
 
-    // max buffer size
-    if MAX_BUFFER_SIZE.set(*ctx_size as usize * 6).is_err() {
-        return Err(String::from(
-            "Fail to set `MAX_BUFFER_SIZE`. It is already set.",
-        ));
-    }
-
     // number of tokens to predict
     let n_predict = matches.get_one::<u64>("n_predict").unwrap();
     println!("[INFO] Number of tokens to predict: {n}", n = n_predict);
@@ -196,11 +212,16 @@ fn main() -> Result<(), String> {
     );
     options.repeat_penalty = *repeat_penalty;
 
-    // reverse_prompt
-    if let Some(reverse_prompt) = matches.get_one::<String>("reverse_prompt") {
-        println!("[INFO] Reverse prompt: {prompt}", prompt = &reverse_prompt);
-        options.reverse_prompt = Some(reverse_prompt.to_string());
+    // reverse_prompt, of which the backend only knows the first one, while the generation stops at any of them
+    let reverse_prompts: Vec<String> = matches
+        .get_many::<String>("reverse_prompt")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    for reverse_prompt in reverse_prompts.iter() {
+        println!("[INFO] Reverse prompt: {prompt}", prompt = reverse_prompt);
     }
+    options.reverse_prompt = reverse_prompts.first().cloned();
 
     // system prompt
     let system_prompt = matches
@@ -221,21 +242,52 @@ fn main() -> Result<(), String> {
         }
     };
 
-    // type of prompt template
-    let prompt_template = matches
-        .get_one::<String>("prompt_template")
-        .unwrap()
-        .to_string();
-    let template_ty = match PromptTemplateType::from_str(&prompt_template) {
-        Ok(template) => template,
-        Err(e) => {
-            return Err(format!(
-                "Fail to parse prompt template type: {msg}",
-                msg = e.to_string()
-            ))
+    // prompt template
+    let template = match matches.get_one::<String>("prompt_template_file") {
+        Some(file) => match CustomPrompt::from_file(file) {
+            Ok(template) => {
+                println!("[INFO] Prompt template file: {file}", file = file);
+                ChatPrompt::CustomPrompt(template)
+            }
+            Err(e) => return Err(format!("Fail to load prompt template file: {msg}", msg = e)),
+        },
+        None => {
+            let prompt_template = matches
+                .get_one::<String>("prompt_template")
+                .unwrap()
+                .to_string();
+            let metadata = match matches.get_one::<String>("model_file") {
+                Some(model_file) => match GgufMetadata::from_file(model_file) {
+                    Ok(metadata) => Some(metadata),
+                    Err(e) => return Err(format!("Fail to read model file: {msg}", msg = e)),
+                },
+                None => None,
+            };
+            let template_ty =
+                match gguf::resolve_prompt_template(&prompt_template, metadata.as_ref()) {
+                    Ok((template_ty, warning)) => {
+                        if let Some(warning) = warning {
+                            println!("[WARNING] {warning}");
+                        }
+                        template_ty
+                    }
+                    Err(e) => return Err(e.to_string()),
+                };
+            println!("[INFO] Prompt template: {ty:?}", ty = &template_ty);
+            let template = match template_ty {
+                PromptTemplateType::Jinja => JinjaPrompt::load(
+                    matches.get_one::<String>("chat_template_file"),
+                    matches.get_one::<String>("model_file"),
+                )
+                .map(ChatPrompt::JinjaPrompt),
+                _ => ChatPrompt::try_from(template_ty),
+            };
+            match template {
+                Ok(template) => template,
+                Err(e) => return Err(format!("Fail to create prompt template: {msg}", msg = e)),
+            }
         }
     };
-    println!("[INFO] Prompt template: {ty:?}", ty = &template_ty);
 
     // log prompts
     let log_prompts = matches.get_flag("log_prompts");
@@ -254,7 +306,10 @@ fn main() -> Result<(), String> {
         options.log_enable = true;
     }
 
-    let template = create_prompt_template(template_ty.clone());
+    // stop the generation at the reverse prompts or at the end markers of the prompt template
+    let mut stops = reverse_prompts;
+    stops.extend(template.stop_sequences());
+
     let mut chat_request = ChatCompletionRequest::default();
     // put system_prompt into the `messages` of chat_request
     if !system_prompt.is_empty() {
@@ -305,6 +360,10 @@ fn main() -> Result<(), String> {
         }
     };
 
+    // the prompt leaves room for the answer, which takes at most half of the context window
+    let budget =
+        (options.ctx_size - std::cmp::min(options.n_predict, options.ctx_size / 2)) as usize;
+
     print_separator();
 
     loop {
@@ -318,9 +377,29 @@ fn main() -> Result<(), String> {
                 user_message,
             ));
 
-        // build prompt
-        let prompt = match template.build(&mut chat_request.messages) {
-            Ok(prompt) => prompt,
+        // build prompt, dropping the oldest turns if the conversation does not fit into the context window
+        let fitted = fit_prompt(
+            &template,
+            &mut chat_request.messages,
+            &[],
+            budget,
+            |prompt| count_tokens(&mut context, prompt),
+        );
+        let prompt = match fitted {
+            Ok(fitted) => {
+                if fitted.dropped_messages > 0 && (log_stat || log_all) {
+                    println!(
+                        "[INFO] Drop the {n} oldest messages to fit the prompt into the context window",
+                        n = fitted.dropped_messages
+                    );
+                }
+                fitted.prompt
+            }
+            Err(e @ PromptError::PromptTooLong { .. }) => {
+                println!("[ERROR] {msg}", msg = e);
+                chat_request.messages.pop();
+                continue;
+            }
             Err(e) => {
                 return Err(format!(
                     "Fail to build chat prompts: {msg}",
@@ -349,17 +428,14 @@ fn main() -> Result<(), String> {
         };
 
         // compute
-        let message = match options.reverse_prompt {
-            Some(ref prompt) => stream_compute(&mut context, Some(prompt.as_str())),
-            None => stream_compute(&mut context, None),
-        };
+        let message = stream_compute(&mut context, &stops);
 
         // put the answer into the `messages` of chat_request
         chat_request
             .messages
             .push(ChatCompletionRequestMessage::new(
                 ChatCompletionRole::Assistant,
-                message,
+                template.post_process(&message),
             ));
     }
 
@@ -383,143 +459,42 @@ fn print_separator() {
     println!("----------------------------------------------------");
 }
 
-fn create_prompt_template(template_ty: PromptTemplateType) -> ChatPrompt {
-    match template_ty {
-        PromptTemplateType::Llama2Chat => {
-            ChatPrompt::Llama2ChatPrompt(chat_prompts::chat::llama::Llama2ChatPrompt::default())
-        }
-        PromptTemplateType::MistralInstruct => ChatPrompt::MistralInstructPrompt(
-            chat_prompts::chat::mistral::MistralInstructPrompt::default(),
-        ),
-        PromptTemplateType::MistralLite => {
-            ChatPrompt::MistralLitePrompt(chat_prompts::chat::mistral::MistralLitePrompt::default())
-        }
-        PromptTemplateType::OpenChat => {
-            ChatPrompt::OpenChatPrompt(chat_prompts::chat::openchat::OpenChatPrompt::default())
-        }
-        PromptTemplateType::CodeLlama => ChatPrompt::CodeLlamaInstructPrompt(
-            chat_prompts::chat::llama::CodeLlamaInstructPrompt::default(),
-        ),
-        PromptTemplateType::BelleLlama2Chat => ChatPrompt::BelleLlama2ChatPrompt(
-            chat_prompts::chat::belle::BelleLlama2ChatPrompt::default(),
-        ),
-        PromptTemplateType::VicunaChat => {
-            ChatPrompt::VicunaChatPrompt(chat_prompts::chat::vicuna::VicunaChatPrompt::default())
-        }
-        PromptTemplateType::Vicuna11Chat => {
-            ChatPrompt::Vicuna11ChatPrompt(chat_prompts::chat::vicuna::Vicuna11ChatPrompt::default())
-        }
-        PromptTemplateType::ChatML => {
-            ChatPrompt::ChatMLPrompt(chat_prompts::chat::chatml::ChatMLPrompt::default())
-        }
-        PromptTemplateType::Baichuan2 => ChatPrompt::Baichuan2ChatPrompt(
-            chat_prompts::chat::baichuan::Baichuan2ChatPrompt::default(),
-        ),
-        PromptTemplateType::WizardCoder => {
-            ChatPrompt::WizardCoderPrompt(chat_prompts::chat::wizard::WizardCoderPrompt::default())
-        }
-        PromptTemplateType::Zephyr => {
-            ChatPrompt::ZephyrChatPrompt(chat_prompts::chat::zephyr::ZephyrChatPrompt::default())
-        }
-        PromptTemplateType::IntelNeural => {
-            ChatPrompt::NeuralChatPrompt(chat_prompts::chat::intel::NeuralChatPrompt::default())
-        }
-        PromptTemplateType::DeepseekChat => ChatPrompt::DeepseekChatPrompt(
-            chat_prompts::chat::deepseek::DeepseekChatPrompt::default(),
-        ),
-        PromptTemplateType::DeepseekCoder => ChatPrompt::DeepseekCoderPrompt(
-            chat_prompts::chat::deepseek::DeepseekCoderPrompt::default(),
-        ),
-    }
+fn _print(message: impl AsRef<str>) {
+    println!("\n[Bot]:\n{}", message.as_ref().trim())
 }
 
-fn _post_process(output: impl AsRef<str>, template_ty: PromptTemplateType) -> String {
-    println!("[DEBUG] Post-processing ...");
-
-    if template_ty == PromptTemplateType::Baichuan2 {
-        if output.as_ref().contains("用户:") {
-            output.as_ref().trim_end_matches("用户:").trim().to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::OpenChat {
-        if output.as_ref().contains("<|end_of_turn|>") {
-            output
-                .as_ref()
-                .trim_end_matches("<|end_of_turn|>")
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::ChatML {
-        if output.as_ref().contains("<|im_start|>") && output.as_ref().contains("<|im_end|>") {
-            let idx_start = output.as_ref().find("<|im_start|>").unwrap();
-            let idx_end = output.as_ref().find("<|im_end|>").unwrap();
-
-            match idx_start <= idx_end {
-                true => output.as_ref().split("<|im_start|>").collect::<Vec<_>>()[0]
-                    .trim()
-                    .to_owned(),
-                false => output.as_ref().split("<|im_end|>").collect::<Vec<_>>()[0]
-                    .trim()
-                    .to_owned(),
-            }
-        } else if output.as_ref().contains("<|im_start|>") {
-            output.as_ref().split("<|im_start|>").collect::<Vec<_>>()[0]
-                .trim()
-                .to_owned()
-        } else if output.as_ref().contains("<|im_end|>") {
-            output.as_ref().split("<|im_end|>").collect::<Vec<_>>()[0]
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::Zephyr
-        || template_ty == PromptTemplateType::MistralLite
-    {
-        if output.as_ref().contains("</s><") {
-            output.as_ref().trim_end_matches("</s><").trim().to_owned()
-        } else if output.as_ref().contains("</s>") {
-            output
-                .as_ref()
-                .strip_suffix("</s>")
-                .unwrap()
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
+/// Counts the tokens of the text by setting it as the input tensor. The backend reports the number of the input tokens as the output tensor at index 1.
+fn count_tokens(context: &mut wasi_nn::GraphExecutionContext, text: &str) -> Result<usize, String> {
+    match context.set_input(0, wasi_nn::TensorType::U8, &[1], text.trim().as_bytes()) {
+        Ok(()) => {}
+        // the text is longer than the context window, so it exceeds any budget
+        Err(wasi_nn::Error::BackendError(wasi_nn::BackendError::PromptTooLong)) => {
+            return Ok(usize::MAX)
         }
-    } else if template_ty == PromptTemplateType::DeepseekChat {
-        if output.as_ref().contains("<|end_of_sentence|>") {
-            output
-                .as_ref()
-                .trim_end_matches("<|end_of_sentence|>")
-                .trim()
-                .to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else if template_ty == PromptTemplateType::BelleLlama2Chat {
-        if output.as_ref().contains("Human:") {
-            output.as_ref().trim_end_matches("Human:").trim().to_owned()
-        } else {
-            output.as_ref().trim().to_owned()
-        }
-    } else {
-        output.as_ref().trim().to_owned()
+        Err(_) => return Err(String::from("Fail to set input tensor")),
     }
-}
 
-fn _print(message: impl AsRef<str>) {
-    println!("\n[Bot]:\n{}", message.as_ref().trim())
+    let output_buffer = match read_wasi_nn_output!(|buffer| context.get_output(1, buffer)) {
+        Ok(output_buffer) => output_buffer,
+        Err(e) => return Err(format!("Fail to get token info: {msg}", msg = e)),
+    };
+    let token_info: serde_json::Value = match serde_json::from_slice(&output_buffer) {
+        Ok(token_info) => token_info,
+        Err(e) => return Err(format!("Fail to parse token info: {msg}", msg = e)),
+    };
+
+    Ok(token_info["input_tokens"].as_u64().unwrap_or_default() as usize)
 }
 
-fn stream_compute(context: &mut wasi_nn::GraphExecutionContext, stop: Option<&str>) -> String {
+fn stream_compute(context: &mut wasi_nn::GraphExecutionContext, stops: &[String]) -> String {
     println!("\n[Bot]");
 
     let mut output = String::new();
+    // the bytes of a character split across tokens are not printed until the character is complete
+    let mut decoder = Utf8Decoder::new();
+    // the text which may begin a stop sequence is not printed until the following tokens tell whether it does
+    let mut matcher = StopMatcher::new(stops);
+    let mut started = false;
     // Compute one token at a time, and get the token using the get_output_single().
     loop {
         match context.compute_single() {
@@ -533,36 +508,48 @@ fn stream_compute(context: &mut wasi_nn::GraphExecutionContext, stop: Option<&st
             }
         }
         // Retrieve the output.
-        let max_output_size = *MAX_BUFFER_SIZE.get().unwrap();
-        let mut output_buffer = vec![0u8; max_output_size];
-        let mut output_size = context.get_output_single(0, &mut output_buffer).unwrap();
-        output_size = std::cmp::min(max_output_size, output_size);
-        let token = String::from_utf8_lossy(&output_buffer[..output_size]).to_string();
+        let output_buffer = match read_wasi_nn_output!(|buffer| context.get_output_single(0, buffer))
+        {
+            Ok(output_buffer) => output_buffer,
+            Err(err) => {
+                println!("Error: {}", err);
+                break;
+            }
+        };
+        let token = decoder.push(&output_buffer);
 
         // remove the redundant characters at the beginning of each answer
-        if output.is_empty() && (token == " " || token == "\n") {
+        if !started && (token.is_empty() || token == " " || token == "\n") {
             continue;
         }
+        started = true;
+
+        print_text(&mut output, &matcher.push(&token));
 
         // trigger the stop condition
-        if stop.is_some() && stop == Some(token.trim()) {
+        if matcher.stopped() {
             break;
         }
-
-        if output.is_empty() && token.starts_with(" ") {
-            print!("{}", token.trim_start());
-        } else {
-            print!("{}", token);
-        }
-        std::io::stdout().flush().unwrap();
-
-        output += &token;
     }
+    print_text(&mut output, &matcher.push(&decoder.flush()));
+    print_text(&mut output, &matcher.flush());
     println!("");
 
     output
 }
 
+/// Prints the text of the answer as it is generated, and appends it to the answer.
+fn print_text(output: &mut String, text: &str) {
+    if output.is_empty() && text.starts_with(' ') {
+        print!("{}", text.trim_start());
+    } else {
+        print!("{}", text);
+    }
+    std::io::stdout().flush().unwrap();
+
+    output.push_str(text);
+}
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 struct Options {
     #[serde(rename = "enable-log")]
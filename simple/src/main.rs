@@ -1,9 +1,8 @@
+use chat_prompts::read_wasi_nn_output;
 use clap::{crate_version, Arg, ArgAction, Command};
-use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 
 const DEFAULT_CTX_SIZE: &str = "4096";
-static CTX_SIZE: OnceCell<usize> = OnceCell::new();
 
 fn main() -> Result<(), String> {
     let matches = Command::new("llama-simple")
@@ -90,9 +89,6 @@ fn main() -> Result<(), String> {
 
     // prompt context size
     let ctx_size = matches.get_one::<u32>("ctx_size").unwrap();
-    CTX_SIZE
-        .set(*ctx_size as usize * 6)
-        .expect("Fail to parse prompt context size");
     println!("[INFO] prompt context size: {size}", size = ctx_size);
 
     // number of tokens to predict
@@ -159,12 +155,9 @@ fn main() -> Result<(), String> {
     context.compute().expect("Failed to complete inference");
 
     // retrieve the output
-    let mut output_buffer = vec![0u8; *CTX_SIZE.get().unwrap()];
-    let mut output_size = context
-        .get_output(0, &mut output_buffer)
+    let output_buffer = read_wasi_nn_output!(|buffer| context.get_output(0, buffer))
         .expect("Failed to get output tensor");
-    output_size = std::cmp::min(*CTX_SIZE.get().unwrap(), output_size);
-    let output = String::from_utf8_lossy(&output_buffer[..output_size]).to_string();
+    let output = String::from_utf8_lossy(&output_buffer).to_string();
 
     println!("\n[Answer]:\n\n{}", output);
 